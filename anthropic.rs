@@ -0,0 +1,237 @@
+use std::time::Duration;
+use serde_json::Value;
+use crate::Role;
+use crate::backend::{ChatBackend, ChatRequest, CancelHandle, FinishReason, Sink, StreamEvent, Usage};
+use crate::error::GptError;
+use crate::gpt;
+use crate::http::{self, Endpoint};
+use crate::sse;
+
+const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1";
+const DEFAULT_MODEL: &str = "claude-3-5-sonnet-latest";
+const API_VERSION: &str = "2023-06-01";
+const MAX_TOKENS: u64 = 4096;
+/// Highest temperature the messages API takes, openai goes up to 2.
+const MAX_TEMPERATURE: f64 = 1.0;
+
+/// Messages API of api.anthropic.com, or whatever ANTHROPIC_BASE_URL points to.
+pub struct Anthropic {
+    endpoint: Endpoint,
+}
+impl Anthropic {
+    pub fn new() -> Result<Self, String> {
+        let url = std::env::var("ANTHROPIC_BASE_URL").unwrap_or(ANTHROPIC_URL.to_string());
+        let endpoint = Endpoint::parse(&url)?;
+        Ok(Anthropic { endpoint })
+    }
+}
+
+fn headers() -> Result<Vec<(&'static str, String)>, GptError> {
+    let secret = std::env::var("ANTHROPIC_API_KEY").map_err(|_err| GptError::missing_env("ANTHROPIC_API_KEY"))?;
+    Ok(vec![("x-api-key", secret), ("anthropic-version", API_VERSION.to_string())])
+}
+
+/// Maps our conversation onto the messages API schema: system messages go to the
+/// top-level `system` field, and consecutive messages of the same role are merged
+/// since the API wants user and assistant to alternate, starting with the user.
+pub fn to_request(conv: &[(Role, String)]) -> (Option<String>, Value) {
+    let mut system: Vec<&str> = Vec::new();
+    let mut messages: Vec<(Role, String)> = Vec::new();
+    for (role, content) in conv {
+        match (role, messages.last_mut()) {
+            (Role::System, _) => system.push(content),
+            (role, Some((last, text))) if last == role => {
+                text.push_str("\n\n");
+                text.push_str(content);
+            }
+            (role, _) => messages.push((*role, content.clone())),
+        }
+    }
+    if messages.first().is_some_and(|(role, _)| *role != Role::User) {
+        messages.insert(0, (Role::User, "...".to_string()));
+    }
+    let messages = messages.iter().map(|(role, content)| serde_json::json!({
+        "role": role.value(),
+        "content": content,
+    })).collect();
+    let system = if system.is_empty() { None } else { Some(system.join("\n\n")) };
+    (system, messages)
+}
+
+fn make_prompt(endpoint: &Endpoint, req: &ChatRequest) -> Result<String, GptError> {
+    let (system, messages) = to_request(&req.messages);
+    let params = &req.params;
+    // NOTE: max_tokens is required by the API, seed isn't supported
+    let mut body = serde_json::json!({
+        "model": req.model,
+        "max_tokens": params.max_tokens.unwrap_or(MAX_TOKENS),
+        "messages": messages,
+        "stream": true
+    });
+    if let Some(system) = system {
+        body["system"] = Value::String(system);
+    }
+    if let Some(temperature) = params.temperature {
+        if temperature > MAX_TEMPERATURE {
+            return Err(GptError::Config(format!("Anthropic takes a temperature of at most {MAX_TEMPERATURE}, not {temperature}")));
+        }
+        body["temperature"] = temperature.into();
+    }
+    if let Some(top_p) = params.top_p {
+        body["top_p"] = top_p.into();
+    }
+    if let Some(stop) = &params.stop {
+        body["stop_sequences"] = stop.clone().into();
+    }
+    let body = body.to_string();
+    Ok(endpoint.make_post("/messages", &headers()?, &body))
+}
+
+/// Maps one event of the messages stream: text comes in `content_block_delta`,
+/// prompt tokens in `message_start`, the stop reason and completion tokens in
+/// `message_delta`. `ping`, `content_block_start` and friends carry nothing for us.
+pub fn on_parse_event(event: &sse::Event, usage: &mut Usage) -> Vec<StreamEvent> {
+    let data = match serde_json::from_str::<Value>(&event.data) {
+        Ok(data) => data,
+        Err(_err) => return vec![GptError::Parse(format!("Could not parse {}", event.data)).into()],
+    };
+    match event.event.as_str() {
+        "message_start" => {
+            usage.prompt_tokens = data["message"]["usage"]["input_tokens"].as_u64().unwrap_or_default();
+            Vec::new()
+        }
+        "content_block_delta" => match data["delta"]["text"].as_str() {
+            Some(text) => vec![StreamEvent::Delta { text: text.to_string() }],
+            None => Vec::new(),
+        },
+        "message_delta" => {
+            let mut events = Vec::new();
+            if let Some(tokens) = data["usage"]["output_tokens"].as_u64() {
+                usage.completion_tokens = tokens;
+                events.push(StreamEvent::Usage(*usage));
+            }
+            if let Some(reason) = data["delta"]["stop_reason"].as_str() {
+                events.push(StreamEvent::Finished { reason: FinishReason::from(reason) });
+            }
+            events
+        }
+        "error" => {
+            let msg = data["error"]["message"].as_str().unwrap_or("unknown error");
+            vec![GptError::Provider(msg.to_string()).into()]
+        }
+        _ => Vec::new(),
+    }
+}
+
+impl ChatBackend for Anthropic {
+    fn default_model(&self) -> &str {
+        DEFAULT_MODEL
+    }
+    fn prompt(&self, req: &ChatRequest, sink: &Sink, cancel: &CancelHandle) -> Result<(), GptError> {
+        let req = make_prompt(&self.endpoint, req)?;
+        let mut stream = self.endpoint.connect(Duration::from_secs(10), cancel)?;
+        let mut decoder = sse::Decoder::default();
+        let mut usage = Usage::default();
+        http::post_streaming(&mut stream, &req, sink, cancel, |data| {
+            decoder.feed(data).iter().flat_map(|x| on_parse_event(x, &mut usage)).collect()
+        })
+    }
+    fn list_models(&self) -> Result<Vec<String>, GptError> {
+        let req = self.endpoint.make_get("/models?limit=1000", &headers()?);
+        let mut stream = self.endpoint.connect(Duration::from_secs(10), &CancelHandle::new())?;
+        Ok(gpt::model_ids(&http::fetch_json(&mut stream, &req)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::Params;
+
+    // NOTE: recorded from the API, trimmed
+    const STREAM: &str = "event: message_start\n\
+data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_01\",\"type\":\"message\",\"role\":\"assistant\",\"content\":[],\"model\":\"claude-3-5-sonnet-20241022\",\"stop_reason\":null,\"stop_sequence\":null,\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\n\
+event: content_block_start\n\
+data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n\
+event: ping\n\
+data: {\"type\": \"ping\"}\n\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}\n\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\" wörld\"}}\n\n\
+event: content_block_stop\n\
+data: {\"type\":\"content_block_stop\",\"index\":0}\n\n\
+event: message_delta\n\
+data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"max_tokens\",\"stop_sequence\":null},\"usage\":{\"output_tokens\":15}}\n\n\
+event: message_stop\n\
+data: {\"type\":\"message_stop\"}\n\n";
+
+    #[test]
+    fn parse_stream() {
+        let mut decoder = sse::Decoder::default();
+        let mut usage = Usage::default();
+        let events: Vec<StreamEvent> = STREAM.as_bytes().chunks(7)
+            .flat_map(|x| decoder.feed(x))
+            .flat_map(|x| on_parse_event(&x, &mut usage))
+            .collect();
+        assert_eq!(events, [
+            StreamEvent::Delta { text: "Hello".to_string() },
+            StreamEvent::Delta { text: " wörld".to_string() },
+            StreamEvent::Usage(Usage { prompt_tokens: 25, completion_tokens: 15 }),
+            StreamEvent::Finished { reason: FinishReason::Length },
+        ]);
+    }
+
+    #[test]
+    fn parse_error_event() {
+        let event = sse::Event {
+            event: "error".to_string(),
+            data: "{\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}".to_string(),
+            id: None,
+        };
+        let events = on_parse_event(&event, &mut Usage::default());
+        assert!(matches!(&events[..], [StreamEvent::Error { message, .. }] if message.contains("Overloaded")), "{events:?}");
+    }
+
+    #[test]
+    fn temperature_too_high() {
+        let endpoint = Endpoint::parse(ANTHROPIC_URL).unwrap();
+        let params = Params { temperature: Some(1.5), ..Default::default() };
+        let req = ChatRequest { model: DEFAULT_MODEL.to_string(), messages: vec![(Role::User, "hi".to_string())], params };
+        assert!(matches!(make_prompt(&endpoint, &req), Err(GptError::Config(msg)) if msg.contains("1.5")));
+    }
+
+    fn request(conv: &[(Role, &str)]) -> (Option<String>, Value) {
+        let conv: Vec<(Role, String)> = conv.iter().map(|(role, text)| (*role, text.to_string())).collect();
+        to_request(&conv)
+    }
+
+    #[test]
+    fn system_to_field() {
+        let (system, messages) = request(&[(Role::System, "be brief"), (Role::User, "hi"), (Role::System, "in french")]);
+        assert_eq!(system.as_deref(), Some("be brief\n\nin french"));
+        assert_eq!(messages, serde_json::json!([{"role": "user", "content": "hi"}]));
+        assert_eq!(request(&[(Role::User, "hi")]).0, None);
+    }
+
+    #[test]
+    fn merge_same_role() {
+        let (_, messages) = request(&[
+            (Role::User, "hi"), (Role::System, "be brief"), (Role::User, "anyone?"), (Role::AI, "hello"), (Role::AI, "sorry"),
+        ]);
+        assert_eq!(messages, serde_json::json!([
+            {"role": "user", "content": "hi\n\nanyone?"},
+            {"role": "assistant", "content": "hello\n\nsorry"},
+        ]));
+    }
+
+    #[test]
+    fn start_with_user() {
+        let (_, messages) = request(&[(Role::AI, "How can I help?"), (Role::User, "hi")]);
+        assert_eq!(messages, serde_json::json!([
+            {"role": "user", "content": "..."},
+            {"role": "assistant", "content": "How can I help?"},
+            {"role": "user", "content": "hi"},
+        ]));
+    }
+}
@@ -0,0 +1,284 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::Sender;
+use crate::Role;
+use std::cell::Cell;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::{anthropic, gpt, local};
+use crate::error::{ErrorKind, GptError};
+use crate::params::Params;
+
+/// Identifies one prompt, so the UI can tell the replies of different requests apart.
+pub type RequestId = u64;
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    socket: Mutex<Option<TcpStream>>,
+}
+
+/// Shared between the UI and a backend thread, lets the UI stop a request.
+/// Cancelling shuts down the socket the backend registered with [`CancelHandle::watch`],
+/// so a read blocked on a slow server returns right away.
+#[derive(Clone, Default)]
+pub struct CancelHandle(Arc<CancelState>);
+impl CancelHandle {
+    pub fn new() -> Self {
+        CancelHandle::default()
+    }
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        if let Some(socket) = self.0.socket.lock().ok().and_then(|mut x| x.take()) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+    /// Registers the socket of the request, it is shut down immediately when the
+    /// request was cancelled while connecting.
+    pub fn watch(&self, socket: &TcpStream) {
+        let Ok(socket) = socket.try_clone() else { return };
+        let Ok(mut slot) = self.0.socket.lock() else { return };
+        if self.is_cancelled() {
+            let _ = socket.shutdown(Shutdown::Both);
+        } else {
+            *slot = Some(socket);
+        }
+    }
+}
+
+/// Token counts reported by the provider for one request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+impl std::ops::Add for Usage {
+    type Output = Usage;
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            prompt_tokens: self.prompt_tokens + other.prompt_tokens,
+            completion_tokens: self.completion_tokens + other.completion_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model finished its answer.
+    Stop,
+    /// The answer hit the token limit.
+    Length,
+    Cancelled,
+    Error,
+    /// Anything else the provider reports, e.g. a content filter.
+    Other(String),
+}
+impl FinishReason {
+    /// Maps the stop reason names used by the different providers, and the ones of [`FinishReason::name`].
+    pub fn from(reason: &str) -> Self {
+        match reason {
+            "stop" | "end_turn" | "stop_sequence" => FinishReason::Stop,
+            "length" | "max_tokens" => FinishReason::Length,
+            "cancelled" => FinishReason::Cancelled,
+            "error" => FinishReason::Error,
+            other => FinishReason::Other(other.to_string()),
+        }
+    }
+    /// Name the reason is saved under.
+    pub fn name(&self) -> &str {
+        match self {
+            FinishReason::Stop => "stop",
+            FinishReason::Length => "length",
+            FinishReason::Cancelled => "cancelled",
+            FinishReason::Error => "error",
+            FinishReason::Other(reason) => reason,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Start { role: Role },
+    Delta { text: String },
+    Usage(Usage),
+    Error { kind: ErrorKind, message: String },
+    /// The request failed before anything was streamed and is sent again after `delay`.
+    Retrying { attempt: u32, max_retries: u32, delay: Duration, reason: String },
+    /// Always the last event of a request.
+    Finished { reason: FinishReason },
+}
+
+impl From<GptError> for StreamEvent {
+    fn from(err: GptError) -> Self {
+        StreamEvent::Error { kind: err.kind(), message: err.to_string() }
+    }
+}
+
+/// Sending half of the channel handed to a backend, every event is tagged with
+/// the id of the request it answers. Should the backend thread die before
+/// finishing, dropping the sink tells the UI instead of leaving it waiting.
+pub struct Sink {
+    id: RequestId,
+    tx: Sender<(RequestId, StreamEvent)>,
+    started: Cell<bool>,
+    finished: Cell<bool>,
+}
+impl Sink {
+    pub fn new(id: RequestId, tx: Sender<(RequestId, StreamEvent)>) -> Self {
+        Sink { id, tx, started: Cell::new(false), finished: Cell::new(false) }
+    }
+    /// Whether the reply started streaming, after which the request can't be retried.
+    pub fn is_started(&self) -> bool {
+        self.started.get()
+    }
+    pub fn send(&self, event: StreamEvent) {
+        match event {
+            StreamEvent::Start { .. } => self.started.set(true),
+            StreamEvent::Finished { .. } => self.finished.set(true),
+            _ => {}
+        }
+        // NOTE: the UI is gone when this fails, nobody is left to tell
+        let _ = self.tx.send((self.id, event));
+    }
+    pub fn start(&self, role: Role) {
+        self.send(StreamEvent::Start { role });
+    }
+    pub fn finish(&self, reason: FinishReason) {
+        self.send(StreamEvent::Finished { reason });
+    }
+    /// Reports an error that ends the request.
+    pub fn fail(&self, err: GptError) {
+        self.send(err.into());
+        self.finish(FinishReason::Error);
+    }
+}
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if !self.finished.get() {
+            let msg = if std::thread::panicking() { "The backend crashed" } else { "The backend stopped without finishing" };
+            self.send(GptError::Internal(msg.to_string()).into());
+            self.finish(FinishReason::Error);
+        }
+    }
+}
+
+/// What to send to the provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<(Role, String)>,
+    /// Already merged from the config, the conversation and the message.
+    pub params: Params,
+}
+
+pub trait ChatBackend: Send + Sync {
+    /// Model used when neither the user nor the conversation picked one.
+    fn default_model(&self) -> &str;
+    /// Sends `req` to the provider and streams the reply into `sink`: `Start`,
+    /// the content deltas, then `Finished`. Implementations stop reading as soon
+    /// as `cancel` is triggered. Failing before `Finished` returns the error
+    /// instead, see [`run`].
+    fn prompt(&self, req: &ChatRequest, sink: &Sink, cancel: &CancelHandle) -> Result<(), GptError>;
+    /// Names of the models the provider offers.
+    fn list_models(&self) -> Result<Vec<String>, GptError>;
+}
+
+/// How often and how patiently a request that failed with a transient error is
+/// sent again, see [`GptError::is_transient`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after that.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_retries: 3, base_delay: Duration::from_secs(1), max_delay: Duration::from_secs(60) }
+    }
+}
+impl RetryPolicy {
+    /// Default policy, tuned by GPTERM_MAX_RETRIES and GPTERM_RETRY_DELAY_MS.
+    pub fn from_env() -> Self {
+        let mut policy = RetryPolicy::default();
+        if let Some(retries) = std::env::var("GPTERM_MAX_RETRIES").ok().and_then(|x| x.parse().ok()) {
+            policy.max_retries = retries;
+        }
+        if let Some(ms) = std::env::var("GPTERM_RETRY_DELAY_MS").ok().and_then(|x| x.parse().ok()) {
+            policy.base_delay = Duration::from_millis(ms);
+        }
+        policy
+    }
+    /// Delay before retry number `attempt` (from 1): what the server asked for if it
+    /// did, else exponential backoff with jitter so clients don't retry in lockstep.
+    /// Never more than `max_delay`.
+    pub fn delay(&self, attempt: u32, err: &GptError) -> Duration {
+        if let Some(after) = err.retry_after() {
+            return after.min(self.max_delay);
+        }
+        let backoff = self.base_delay.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(self.max_delay);
+        // NOTE: no need for a real rng, the clock's nanoseconds are random enough here
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        backoff / 2 + backoff.mul_f64((nanos % 1000) as f64 / 2000.0)
+    }
+}
+
+/// Runs one request on `backend`, retrying transient failures as long as nothing
+/// was streamed yet. Failures end up in `sink` as an error event.
+pub fn run(backend: &dyn ChatBackend, req: &ChatRequest, sink: &Sink, cancel: &CancelHandle, retry: &RetryPolicy) {
+    let mut attempt = 0;
+    loop {
+        let err = match backend.prompt(req, sink, cancel) {
+            Ok(()) => return,
+            Err(_err) if cancel.is_cancelled() => return sink.finish(FinishReason::Cancelled),
+            Err(err) => err,
+        };
+        // NOTE: when the server asks for a longer wait, the error tells the user how long instead
+        let too_long = err.retry_after().is_some_and(|x| x > retry.max_delay);
+        if !err.is_transient() || sink.is_started() || attempt >= retry.max_retries || too_long {
+            return sink.fail(err);
+        }
+        attempt += 1;
+        let delay = retry.delay(attempt, &err);
+        sink.send(StreamEvent::Retrying { attempt, max_retries: retry.max_retries, delay, reason: err.to_string() });
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            if cancel.is_cancelled() {
+                return sink.finish(FinishReason::Cancelled);
+            }
+            std::thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+        }
+    }
+}
+
+/// Stands in when the configured backend could not be set up, every prompt is
+/// answered with the reason.
+pub struct Unavailable(pub String);
+impl ChatBackend for Unavailable {
+    fn default_model(&self) -> &str {
+        ""
+    }
+    fn prompt(&self, _req: &ChatRequest, _sink: &Sink, _cancel: &CancelHandle) -> Result<(), GptError> {
+        Err(GptError::Config(self.0.clone()))
+    }
+    fn list_models(&self) -> Result<Vec<String>, GptError> {
+        Err(GptError::Config(self.0.clone()))
+    }
+}
+
+/// Picks the backend named by GPTERM_BACKEND: openai (default), azure, anthropic,
+/// ollama or llama.cpp.
+pub fn from_env() -> Result<Arc<dyn ChatBackend>, String> {
+    let name = std::env::var("GPTERM_BACKEND").unwrap_or_default();
+    Ok(match name.to_lowercase().as_str() {
+        "" | "openai" => Arc::new(gpt::OpenAi::new()?),
+        "azure" => Arc::new(gpt::OpenAi::azure()?),
+        "anthropic" => Arc::new(anthropic::Anthropic::new()?),
+        "ollama" => Arc::new(local::Local::ollama()?),
+        "llama.cpp" | "llamacpp" => Arc::new(local::Local::llama_cpp()?),
+        _ => return Err(format!("Unknown backend {name}")),
+    })
+}
@@ -0,0 +1,22 @@
+use std::path::Path;
+
+const TABLES: [&str; 2] = ["cl100k_base", "o200k_base"];
+
+/// Compiles the tiktoken tables into the binary when they are in `tables/`, see
+/// `tokenizer::bundled`. They aren't part of the repository, GPTERM_BUNDLE_TABLES
+/// makes a build without them fail instead of leaving the counting to the data dir.
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(bundled_tables)");
+    println!("cargo::rerun-if-changed=tables");
+    println!("cargo::rerun-if-env-changed=GPTERM_BUNDLE_TABLES");
+    let missing: Vec<String> = TABLES.iter()
+        .map(|name| format!("tables/{name}.tiktoken"))
+        .filter(|x| !Path::new(x).exists())
+        .collect();
+    if missing.is_empty() {
+        println!("cargo::rustc-cfg=bundled_tables");
+    } else if std::env::var_os("GPTERM_BUNDLE_TABLES").is_some() {
+        panic!("GPTERM_BUNDLE_TABLES is set but {} could not be found, download it from \
+                https://openaipublic.blob.core.windows.net/encodings/", missing.join(", "));
+    }
+}
@@ -0,0 +1,74 @@
+/// Something typed in the input line starting with '/', run locally instead of
+/// being sent to the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `/model [name]`: switch the conversation to `name`, or show the current one.
+    Model(Option<String>),
+    /// `/set [name [value]]`: set a parameter of the conversation, unset it when
+    /// `value` is missing, or show them all without `name`.
+    Set { name: Option<String>, value: Option<String> },
+    /// `/once name value`: set a parameter for the next message only. `/temp value`
+    /// is short for `/once temperature value`.
+    Once { name: String, value: String },
+    /// `/persona [name]`: switch the conversation to the persona `name`, or list them.
+    Persona(Option<String>),
+    /// `/system [prompt]`: set the system prompt of the conversation, remove it without `prompt`.
+    System(Option<String>),
+    /// `/new [title]`: start a new conversation.
+    New(Option<String>),
+    /// `/open name`: switch to the conversation with that title or id.
+    Open(String),
+    /// `/rename title`: retitle the current conversation.
+    Rename(String),
+    /// `/delete [name]`: delete a conversation, the current one without `name`.
+    Delete(Option<String>),
+    /// `/list`: list the conversations.
+    List,
+    /// `/regenerate`: ask for another reply to the last prompt, the old one is kept on a branch.
+    Regenerate,
+}
+
+pub const COMMANDS: &[&str] = &[
+    "/model", "/set", "/once", "/temp", "/persona", "/system", "/new", "/open", "/rename", "/delete", "/list",
+    "/regenerate",
+];
+
+impl Command {
+    /// `None` when `input` is a regular prompt.
+    pub fn parse(input: &str) -> Option<Result<Self, String>> {
+        let input = input.trim();
+        if !input.starts_with('/') {
+            return None;
+        }
+        let (name, arg) = match input.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim().to_string()).filter(|x| !x.is_empty())),
+            None => (input, None),
+        };
+        let (param, value) = match arg.as_deref().map(|x| x.split_once(char::is_whitespace)) {
+            Some(Some((param, value))) => (Some(param.to_string()), Some(value.trim().to_string())),
+            Some(None) => (arg.clone(), None),
+            None => (None, None),
+        };
+        Some(match name {
+            "/model" => Ok(Command::Model(arg)),
+            "/set" => Ok(Command::Set { name: param, value }),
+            "/once" => match (param, value) {
+                (Some(name), Some(value)) => Ok(Command::Once { name, value }),
+                _ => Err("Usage: /once <name> <value>".to_string()),
+            },
+            "/persona" => Ok(Command::Persona(arg)),
+            "/system" => Ok(Command::System(arg)),
+            "/new" => Ok(Command::New(arg)),
+            "/open" => arg.map(Command::Open).ok_or("Usage: /open <name>".to_string()),
+            "/rename" => arg.map(Command::Rename).ok_or("Usage: /rename <title>".to_string()),
+            "/delete" => Ok(Command::Delete(arg)),
+            "/list" => Ok(Command::List),
+            "/regenerate" => Ok(Command::Regenerate),
+            "/temp" => match arg {
+                Some(value) => Ok(Command::Once { name: "temperature".to_string(), value }),
+                None => Err("Usage: /temp <value>".to_string()),
+            },
+            _ => Err(format!("Unknown command {name}, try one of {}", COMMANDS.join(", "))),
+        })
+    }
+}
@@ -0,0 +1,181 @@
+use std::path::PathBuf;
+use serde_json::Value;
+use crate::backend::Usage;
+use crate::params::{Params, NAMES};
+
+const USAGE: &str = "Usage: gpterm [--model <name>] [--persona <name>] [--config <file>] [--temperature <t>] [--top-p <p>] [--max-tokens <n>] [--stop <s>] [--seed <n>]";
+
+/// `$XDG_CONFIG_HOME/gpterm`, falling back to `~/.config/gpterm`.
+pub fn config_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("gpterm")),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("gpterm")),
+    }
+}
+
+/// `$XDG_DATA_HOME/gpterm`, falling back to `~/.local/share/gpterm`.
+pub fn data_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("gpterm")),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share").join("gpterm")),
+    }
+}
+
+/// Settings from the config file, the environment and the command line, later
+/// ones overriding earlier ones. The config file is json:
+/// `{"model": "gpt-4o-mini", "params": {"temperature": 0.7}, "prices": {"gpt-4o-mini": {"prompt": 0.15, "completion": 0.6}},
+/// "personas": {"sql helper": {..}}, "persona": "sql helper"}`, see [`Persona`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    /// Model picked explicitly on the command line, it wins over the conversation's.
+    pub model_arg: Option<String>,
+    /// Model to use when neither the command line nor the conversation has one.
+    pub model: Option<String>,
+    /// Defaults for every conversation, which can override them with `/set`.
+    pub params: Params,
+    /// Per model, to estimate what a conversation costs.
+    pub prices: Vec<(String, Price)>,
+    /// Context window per model, for models gpterm doesn't know or to send less.
+    pub context_limits: Vec<(String, usize)>,
+    pub personas: Vec<Persona>,
+    /// Persona picked on the command line, applied to the conversation it opens.
+    pub persona_arg: Option<String>,
+    /// Persona new conversations start with.
+    pub persona: Option<String>,
+    /// Cheaper model to write the titles of conversations with, else their own.
+    pub title_model: Option<String>,
+}
+
+/// A named setup to chat with: `{"system": "You review rust code", "model": "gpt-4o", "params": {"temperature": 0.2}}`,
+/// every field optional.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Persona {
+    pub name: String,
+    /// System prompt the conversation starts with.
+    pub system: Option<String>,
+    pub model: Option<String>,
+    pub params: Params,
+}
+impl Persona {
+    fn from_json(name: &str, data: &Value) -> Self {
+        Persona {
+            name: name.to_string(),
+            system: data["system"].as_str().map(|x| x.to_string()),
+            model: data["model"].as_str().map(|x| x.to_string()),
+            params: Params::from_json(&data["params"]),
+        }
+    }
+}
+
+/// Dollars per million tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+impl Price {
+    pub fn cost(&self, usage: Usage) -> f64 {
+        (usage.prompt_tokens as f64 * self.prompt + usage.completion_tokens as f64 * self.completion) / 1e6
+    }
+}
+
+/// Price of `model`: the entry named exactly like it, else the longest one it
+/// starts with, so `gpt-4o` also covers `gpt-4o-2024-08-06`.
+pub fn price(prices: &[(String, Price)], model: &str) -> Option<Price> {
+    prices.iter()
+        .filter(|(name, _)| model.starts_with(name.as_str()))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| *price)
+}
+
+impl Config {
+    /// Loads the config, problems that should not stop gpterm from starting are
+    /// returned as warnings.
+    pub fn load() -> Result<(Self, Vec<String>), String> {
+        let mut config = Config::default();
+        let mut warnings = Vec::new();
+        let mut args = std::env::args().skip(1);
+        let mut path = config_dir().map(|dir| dir.join("config.json"));
+        let mut explicit_path = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-m" | "--model" => config.model_arg = Some(args.next().ok_or(USAGE)?),
+                "-p" | "--persona" => config.persona_arg = Some(args.next().ok_or(USAGE)?),
+                "-c" | "--config" => {
+                    path = Some(PathBuf::from(args.next().ok_or(USAGE)?));
+                    explicit_path = true;
+                }
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if arg.strip_prefix("--").is_some_and(|x| NAMES.contains(&x.replace('-', "_").as_str())) => {
+                    let value = args.next().ok_or(USAGE)?;
+                    config.params.set(&arg[2..].replace('-', "_"), Some(&value))?;
+                }
+                _ => return Err(format!("Unexpected argument {arg}\n{USAGE}")),
+            }
+        }
+        if let Some(path) = path {
+            match std::fs::read(&path) {
+                Ok(data) => match serde_json::from_slice::<Value>(&data) {
+                    Ok(data) => config.apply(&data),
+                    Err(err) => warnings.push(format!("Could not parse {}: {err}", path.display())),
+                },
+                Err(err) if explicit_path || err.kind() != std::io::ErrorKind::NotFound => {
+                    warnings.push(format!("Could not read {}: {err}", path.display()));
+                }
+                Err(_err) => {}
+            }
+        }
+        if let Ok(model) = std::env::var("GPTERM_MODEL") {
+            config.model = Some(model);
+        }
+        if let Some(name) = config.persona_arg.as_deref().filter(|x| config.persona(x).is_none()) {
+            return Err(format!("Unknown persona {name}, the config file defines {}", config.persona_names()));
+        }
+        if let Some(name) = config.persona.as_deref().filter(|x| config.persona(x).is_none()) {
+            warnings.push(format!("Unknown persona {name} in the config file"));
+        }
+        Ok((config, warnings))
+    }
+
+    pub fn persona(&self, name: &str) -> Option<&Persona> {
+        self.personas.iter().find(|x| x.name == name)
+    }
+    /// For messages listing the personas.
+    pub fn persona_names(&self) -> String {
+        if self.personas.is_empty() {
+            return "none".to_string();
+        }
+        self.personas.iter().map(|x| x.name.as_str()).collect::<Vec<_>>().join(", ")
+    }
+
+    fn apply(&mut self, data: &Value) {
+        if let Some(model) = data["model"].as_str() {
+            self.model = Some(model.to_string());
+        }
+        if data["params"].is_object() {
+            // NOTE: the command line was parsed first, its parameters win
+            self.params = Params::from_json(&data["params"]).merge(&self.params);
+        }
+        for (model, price) in data["prices"].as_object().into_iter().flatten() {
+            let price = Price {
+                prompt: price["prompt"].as_f64().unwrap_or_default(),
+                completion: price["completion"].as_f64().unwrap_or_default(),
+            };
+            self.prices.push((model.clone(), price));
+        }
+        for (name, persona) in data["personas"].as_object().into_iter().flatten() {
+            self.personas.push(Persona::from_json(name, persona));
+        }
+        if let Some(persona) = data["persona"].as_str() {
+            self.persona = Some(persona.to_string());
+        }
+        if let Some(model) = data["title_model"].as_str() {
+            self.title_model = Some(model.to_string());
+        }
+        for (model, limit) in data["context_limits"].as_object().into_iter().flatten() {
+            if let Some(limit) = limit.as_u64() {
+                self.context_limits.push((model.clone(), limit as usize));
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::ops::Range;
+use crate::Role;
+use crate::tokenizer::Tokenizer;
+
+/// Context windows of well known models, by name prefix, the longest match wins.
+const CONTEXT_LIMITS: &[(&str, usize)] = &[
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-4", 8_192),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("claude", 200_000),
+    ("llama3", 8_192),
+    ("llama3.1", 131_072),
+    ("llama3.2", 131_072),
+];
+/// For models missing from the table and the config.
+const DEFAULT_LIMIT: usize = 8_192;
+/// Tokens every message costs on top of its content, for the role and delimiters.
+const MESSAGE_OVERHEAD: usize = 4;
+/// Tokens priming the reply.
+const REPLY_OVERHEAD: usize = 3;
+
+/// Context window of `model`, from the config's `context_limits` or the table above.
+pub fn limit(overrides: &[(String, usize)], model: &str) -> usize {
+    let builtin = CONTEXT_LIMITS.iter().map(|(name, limit)| (*name, *limit));
+    overrides.iter().map(|(name, limit)| (name.as_str(), *limit))
+        .filter(|(name, _)| model.starts_with(name))
+        .max_by_key(|(name, _)| name.len())
+        .or_else(|| builtin.filter(|(name, _)| model.starts_with(name)).max_by_key(|(name, _)| name.len()))
+        .map_or(DEFAULT_LIMIT, |(_, limit)| limit)
+}
+
+/// What is left of a conversation once it fits the context window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fit {
+    /// Messages that are not sent, empty when everything fits.
+    pub dropped: Range<usize>,
+    /// Tokens of the messages sent.
+    pub tokens: usize,
+}
+
+impl Fit {
+    /// The messages sent.
+    pub fn apply(&self, messages: &[(Role, String)]) -> Vec<(Role, String)> {
+        messages.iter().enumerate()
+            .filter(|(i, _)| !self.dropped.contains(i))
+            .map(|(_, x)| x.clone())
+            .collect()
+    }
+}
+
+/// Drops the oldest turns of `messages` until they leave `reserve` tokens of the
+/// `limit` for the reply. System messages the conversation starts with and the
+/// last message are always kept.
+pub fn fit(messages: &[(Role, String)], tokenizer: &Tokenizer, limit: usize, reserve: usize) -> Fit {
+    let counts: Vec<usize> = messages.iter()
+        .map(|(_, content)| tokenizer.count(content) + MESSAGE_OVERHEAD)
+        .collect();
+    let pinned = messages.iter().take_while(|(role, _)| *role == Role::System).count();
+    let budget = limit.saturating_sub(reserve + REPLY_OVERHEAD);
+    let mut tokens: usize = counts.iter().sum();
+    let mut end = pinned;
+    while tokens > budget && end + 1 < messages.len() {
+        tokens -= counts[end];
+        end += 1;
+    }
+    // NOTE: a reply without the prompt it answers is confusing, drop them together
+    while end > pinned && end + 1 < messages.len() && messages[end].0 == Role::AI {
+        tokens -= counts[end];
+        end += 1;
+    }
+    Fit { dropped: pinned..end, tokens }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message of `tokens` tokens for [`Tokenizer::Estimate`], plus the overhead.
+    fn msg(role: Role, tokens: usize) -> (Role, String) {
+        (role, "x".repeat(tokens * 4))
+    }
+
+    #[test]
+    fn all_fit() {
+        let messages = [msg(Role::System, 1), msg(Role::User, 10), msg(Role::AI, 10)];
+        let fit = fit(&messages, &Tokenizer::Estimate, 1000, 100);
+        assert!(fit.dropped.is_empty());
+        assert_eq!(fit.tokens, 21 + 3 * MESSAGE_OVERHEAD);
+        assert_eq!(fit.apply(&messages), messages);
+    }
+
+    #[test]
+    fn keep_system() {
+        let messages = [msg(Role::System, 1), msg(Role::User, 10), msg(Role::AI, 10), msg(Role::User, 1)];
+        let fit = fit(&messages, &Tokenizer::Estimate, 20 + REPLY_OVERHEAD, 0);
+        assert_eq!(fit.dropped, 1..3);
+        assert_eq!(fit.tokens, 2 + 2 * MESSAGE_OVERHEAD);
+        assert_eq!(fit.apply(&messages), [messages[0].clone(), messages[3].clone()]);
+    }
+
+    #[test]
+    fn keep_last() {
+        let messages = [msg(Role::User, 1), msg(Role::AI, 1), msg(Role::User, 100)];
+        let fit = fit(&messages, &Tokenizer::Estimate, 50 + REPLY_OVERHEAD, 0);
+        assert_eq!(fit.dropped, 0..2);
+        assert_eq!(fit.tokens, 100 + MESSAGE_OVERHEAD);
+    }
+
+    #[test]
+    fn drop_reply_with_prompt() {
+        let messages = [msg(Role::User, 10), msg(Role::AI, 10), msg(Role::User, 10), msg(Role::AI, 10), msg(Role::User, 1)];
+        // NOTE: dropping the first prompt is enough, its reply goes along
+        let fit = fit(&messages, &Tokenizer::Estimate, 50 + REPLY_OVERHEAD, 0);
+        assert_eq!(fit.dropped, 0..2);
+        assert_eq!(fit.tokens, 21 + 3 * MESSAGE_OVERHEAD);
+    }
+}
@@ -0,0 +1,516 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::de::Error;
+use serde_json::Value;
+use crate::Role;
+use crate::backend::{FinishReason, Usage};
+use crate::params::Params;
+
+/// Version of the conversation files written, 1 being the files from before
+/// they had one, see [`migrate`].
+pub const FORMAT_VERSION: u64 = 2;
+
+/// Unix time in seconds.
+fn now() -> Option<u64> {
+    SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|x| x.as_secs())
+}
+
+/// How a message came to be, saved along with it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Meta {
+    /// Model that wrote the reply, assistant messages only.
+    pub model: Option<String>,
+    /// Tokens the provider billed for the reply, assistant messages only.
+    pub usage: Option<Usage>,
+    /// How the reply ended, assistant messages only.
+    pub finish_reason: Option<FinishReason>,
+    /// Set with `/once` for this message only.
+    pub params: Params,
+}
+
+impl Meta {
+    /// `{"model": "gpt-4o", "usage": {"prompt_tokens": 9, "completion_tokens": 1}, "finish_reason": "stop", "params": {"seed": 1}}`,
+    /// every field only when known.
+    pub fn to_json(&self) -> Value {
+        let mut data = serde_json::json!({});
+        if let Some(model) = &self.model {
+            data["model"] = model.as_str().into();
+        }
+        if let Some(usage) = self.usage {
+            data["usage"] = serde_json::json!({
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+            });
+        }
+        if let Some(reason) = &self.finish_reason {
+            data["finish_reason"] = reason.name().into();
+        }
+        if !self.params.is_empty() {
+            data["params"] = self.params.to_json();
+        }
+        data
+    }
+    pub fn from_json(data: &Value) -> Self {
+        let usage = match (data["usage"]["prompt_tokens"].as_u64(), data["usage"]["completion_tokens"].as_u64()) {
+            (Some(prompt_tokens), Some(completion_tokens)) => Some(Usage { prompt_tokens, completion_tokens }),
+            _ => None,
+        };
+        Meta {
+            model: data["model"].as_str().map(|x| x.to_string()),
+            usage,
+            finish_reason: data["finish_reason"].as_str().map(FinishReason::from),
+            params: Params::from_json(&data["params"]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// Unique within the conversation, see [`Conversation::next_id`].
+    pub id: u64,
+    /// When the message was written, unknown for the ones from version 1 files.
+    pub timestamp: Option<u64>,
+    pub role: Role,
+    pub content: String,
+    pub meta: Meta,
+}
+
+impl Message {
+    pub fn new(id: u64, role: Role, content: String) -> Self {
+        Message { id, timestamp: now(), role, content, meta: Meta::default() }
+    }
+    /// `{"id": 2, "timestamp": 1760659200, "role": "assistant", "content": "hello", "meta": {...}}`,
+    /// see [`Meta::to_json`].
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "id": self.id,
+            "timestamp": self.timestamp,
+            "role": self.role,
+            "content": self.content,
+            "meta": self.meta.to_json(),
+        })
+    }
+    pub fn from_json(data: &Value) -> Result<Self, serde_json::Error> {
+        let id = data["id"].as_u64().ok_or(serde_json::Error::custom("Message without id"))?;
+        let role = serde_json::from_value(data["role"].clone())?;
+        let content = data["content"].as_str().ok_or(serde_json::Error::custom("Message without content"))?;
+        Ok(Message {
+            id,
+            timestamp: data["timestamp"].as_u64(),
+            role,
+            content: content.to_string(),
+            meta: Meta::from_json(&data["meta"]),
+        })
+    }
+}
+
+/// A message off the active path of the conversation, left behind by an edit,
+/// a regeneration or a switch to another branch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    /// Id of the message it follows, None for the first messages.
+    pub parent: Option<u64>,
+    pub message: Message,
+}
+
+impl Node {
+    /// The message with `"parent": 2` added, see [`Message::to_json`].
+    pub fn to_json(&self) -> Value {
+        let mut data = self.message.to_json();
+        data["parent"] = self.parent.into();
+        data
+    }
+    pub fn from_json(data: &Value) -> Result<Self, serde_json::Error> {
+        Ok(Node { parent: data["parent"].as_u64(), message: Message::from_json(data)? })
+    }
+}
+
+/// Upgrades a version 1 file, a bare array of `["role", "content"]` pairs, to the current format.
+fn migrate(data: Value) -> Result<Value, serde_json::Error> {
+    let Value::Array(pairs) = data else {
+        return Err(serde_json::Error::custom("Expected an array of messages"));
+    };
+    let mut messages = Vec::new();
+    for (i, pair) in pairs.into_iter().enumerate() {
+        let Some([role, content]) = pair.as_array().map(|x| x.as_slice()) else {
+            return Err(serde_json::Error::custom("Expected a [role, content] pair"));
+        };
+        // NOTE: there were no system prompts back then, only errors shown as system messages
+        let role = if role == "system" { "notice".into() } else { role.clone() };
+        messages.push(serde_json::json!({ "id": i as u64 + 1, "role": role, "content": content }));
+    }
+    Ok(serde_json::json!({ "version": FORMAT_VERSION, "messages": messages }))
+}
+
+/// Backups kept of every conversation file, as `<file>.1` (the newest) to `<file>.3`.
+pub const BACKUPS: usize = 3;
+/// A new backup is taken when the newest one is older than this, so saving
+/// while a reply streams in doesn't rotate out the older versions.
+const BACKUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Backups of the conversation file `path`, the newest first.
+pub fn backups(path: &Path) -> Vec<PathBuf> {
+    (1..=BACKUPS).map(|i| with_suffix(path, &format!(".{i}"))).collect()
+}
+
+/// Copies `path` to its first backup, shifting the older ones, unless the first
+/// backup was taken recently.
+fn rotate_backups(path: &Path) -> std::io::Result<()> {
+    let backups = backups(path);
+    let recent = std::fs::metadata(&backups[0]).and_then(|x| x.modified())
+        .is_ok_and(|x| x.elapsed().unwrap_or_default() < BACKUP_INTERVAL);
+    if !path.exists() || recent {
+        return Ok(());
+    }
+    for i in (1..backups.len()).rev() {
+        if backups[i - 1].exists() {
+            std::fs::rename(&backups[i - 1], &backups[i])?;
+        }
+    }
+    std::fs::copy(path, &backups[0])?;
+    Ok(())
+}
+
+/// A chat and the settings it is held with, saved as
+/// `{"version": 2, "title": "rust questions", "model": "gpt-4o", "persona": null, "params": {"temperature": 0.2}, "messages": [...], "branches": [...]}`,
+/// see [`Message::to_json`] and [`Node::to_json`]. Older files are upgraded on load, see [`migrate`].
+///
+/// The messages form a tree: edits and regenerations fork it, and `messages` is
+/// the path through it that is shown and sent, the rest are `branches`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Conversation {
+    /// Name it is listed under, see [`crate::store::Store`].
+    pub title: Option<String>,
+    pub model: Option<String>,
+    /// Persona the conversation was set up with, see [`crate::config::Persona`].
+    pub persona: Option<String>,
+    /// Set with `/set`, they override the ones from the config.
+    pub params: Params,
+    /// The active path, each message follows the one before it.
+    pub messages: Vec<Message>,
+    /// Messages off the active path, the ones that were on it most recently last.
+    pub branches: Vec<Node>,
+}
+
+impl Conversation {
+    /// The file contents, with the keys in this order so a file cut off in the
+    /// messages keeps the rest, see [`salvage`].
+    pub fn to_json(&self) -> String {
+        let fields: [(&str, Value); 7] = [
+            ("version", FORMAT_VERSION.into()),
+            ("title", self.title.clone().into()),
+            ("model", self.model.clone().into()),
+            ("persona", self.persona.clone().into()),
+            ("params", self.params.to_json()),
+            ("branches", self.branches.iter().map(Node::to_json).collect()),
+            ("messages", self.messages.iter().map(Message::to_json).collect()),
+        ];
+        let fields: Vec<String> = fields.iter().map(|(key, value)| format!("\"{key}\":{value}")).collect();
+        format!("{{{}}}", fields.join(","))
+    }
+    pub fn from_json(data: Value) -> Result<Self, serde_json::Error> {
+        let data = match data["version"].as_u64() {
+            Some(version) if version > FORMAT_VERSION => {
+                return Err(serde_json::Error::custom(format!("Format version {version} is too new, update gpterm")));
+            }
+            Some(FORMAT_VERSION) => data,
+            _ => migrate(data)?,
+        };
+        let title = data["title"].as_str().map(|x| x.to_string());
+        let model = data["model"].as_str().map(|x| x.to_string());
+        let persona = data["persona"].as_str().map(|x| x.to_string());
+        let params = Params::from_json(&data["params"]);
+        let messages = match data["messages"].as_array() {
+            Some(messages) => messages.iter().map(Message::from_json).collect::<Result<_, _>>()?,
+            None => return Err(serde_json::Error::custom("Expected an array of messages")),
+        };
+        let branches = match data["branches"].as_array() {
+            Some(branches) => branches.iter().map(Node::from_json).collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        Ok(Conversation { title, model, persona, params, messages, branches })
+    }
+    /// The messages of all branches.
+    pub fn all_messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter().chain(self.branches.iter().map(|x| &x.message))
+    }
+    /// Id of the message the one at `index` of the active path follows.
+    pub fn parent(&self, index: usize) -> Option<u64> {
+        index.checked_sub(1).map(|i| self.messages[i].id)
+    }
+    /// Moves the messages from `index` on off the active path, to continue it differently.
+    pub fn fork(&mut self, index: usize) {
+        let mut parent = self.parent(index);
+        for message in self.messages.split_off(index) {
+            let id = message.id;
+            self.branches.push(Node { parent, message });
+            parent = Some(id);
+        }
+    }
+    /// Ids of the message at `index` of the active path and of the ones on other
+    /// branches following the same message, in the order they were written.
+    fn siblings(&self, index: usize) -> Vec<u64> {
+        let parent = self.parent(index);
+        let mut ids: Vec<u64> = self.branches.iter().filter(|x| x.parent == parent).map(|x| x.message.id).collect();
+        ids.push(self.messages[index].id);
+        ids.sort();
+        ids
+    }
+    /// Position of the message at `index` among its siblings, from 1, and their number.
+    pub fn version(&self, index: usize) -> (usize, usize) {
+        let siblings = self.siblings(index);
+        (siblings.iter().position(|x| *x == self.messages[index].id).unwrap_or_default() + 1, siblings.len())
+    }
+    /// Switches the active path at `index` to the sibling `step` places later, or
+    /// earlier for a negative `step`, wrapping around. It goes on along the
+    /// branch that was on the active path last.
+    pub fn switch_branch(&mut self, index: usize, step: isize) {
+        let siblings = self.siblings(index);
+        if siblings.len() < 2 {
+            return;
+        }
+        let next = (self.version(index).0 as isize - 1 + step).rem_euclid(siblings.len() as isize);
+        self.fork(index);
+        let mut next = Some(siblings[next as usize]);
+        while let Some(id) = next {
+            let Some(i) = self.branches.iter().position(|x| x.message.id == id) else { break };
+            self.messages.push(self.branches.remove(i).message);
+            next = self.branches.iter().rev().find(|x| x.parent == Some(id)).map(|x| x.message.id);
+        }
+    }
+    /// Makes the branches following `old` follow `new` instead, for when the
+    /// message they forked from is replaced.
+    pub fn reparent(&mut self, old: Option<u64>, new: Option<u64>) {
+        for node in &mut self.branches {
+            if node.parent == old {
+                node.parent = new;
+            }
+        }
+    }
+    /// Id for a new message.
+    pub fn next_id(&self) -> u64 {
+        self.all_messages().map(|x| x.id).max().unwrap_or_default() + 1
+    }
+    /// Appends a message written now.
+    pub fn push(&mut self, role: Role, content: String) -> &mut Message {
+        self.messages.push(Message::new(self.next_id(), role, content));
+        self.messages.last_mut().unwrap()
+    }
+    /// What is sent to the model, along with the index of each message: everything but notices.
+    pub fn history(&self) -> Vec<(usize, (Role, String))> {
+        self.messages.iter().enumerate()
+            .filter(|(_, x)| x.role != Role::Notice)
+            .map(|(i, x)| (i, (x.role, x.content.clone())))
+            .collect()
+    }
+    /// Tokens billed for the whole conversation, all branches included.
+    pub fn usage(&self) -> Usage {
+        self.all_messages().filter_map(|x| x.meta.usage).fold(Usage::default(), |total, x| total + x)
+    }
+    /// Replaces the file atomically: the conversation is written and synced to a
+    /// temporary file that is then renamed over it, so a crash leaves either the
+    /// old or the new version. The old version is kept as a backup first, see [`rotate_backups`].
+    pub fn save(&self, file_path: &Path) -> Result<(), String> {
+        let err = |err: std::io::Error| format!("Could not save {}: {err}", file_path.display());
+        let tmp = with_suffix(file_path, ".tmp");
+        let mut file = File::create(&tmp).map_err(err)?;
+        file.write_all(self.to_json().as_bytes()).map_err(err)?;
+        file.sync_all().map_err(err)?;
+        drop(file);
+        rotate_backups(file_path).map_err(err)?;
+        std::fs::rename(&tmp, file_path).map_err(err)?;
+        // NOTE: makes the rename itself durable, not supported everywhere
+        if let Some(dir) = file_path.parent().and_then(|x| File::open(x).ok()) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+    fn parse(data: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice::<Value>(data).and_then(Conversation::from_json)
+    }
+    pub fn load(file_path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(file_path).map_err(|err| format!("Could not read file {}: {err}", file_path.display()))?;
+        Conversation::parse(&data).map_err(|err| format!("Could not parse file {}: {err}", file_path.display()))
+    }
+    /// Like [`Conversation::load`], but a corrupt file is moved aside to
+    /// `<file>.corrupt` so saving doesn't overwrite it, and what can be read of it
+    /// is salvaged, see [`salvage`]. Also returns what happened in that case.
+    pub fn recover(file_path: &Path) -> Result<(Self, Option<String>), String> {
+        let data = std::fs::read(file_path).map_err(|err| format!("Could not read file {}: {err}", file_path.display()))?;
+        let err = match Conversation::parse(&data) {
+            Ok(conv) => return Ok((conv, None)),
+            Err(err) => format!("Could not parse file {}: {err}", file_path.display()),
+        };
+        let Some(conv) = salvage(&data) else { return Err(err) };
+        let mut corrupt = with_suffix(file_path, ".corrupt");
+        let mut n = 1;
+        while corrupt.exists() {
+            n += 1;
+            corrupt = with_suffix(file_path, &format!(".corrupt-{n}"));
+        }
+        std::fs::rename(file_path, &corrupt).map_err(|x| format!("{err}, could not move it aside: {x}"))?;
+        let mut note = format!("{err}\nIt was moved to {}, {} messages could be salvaged", corrupt.display(), conv.messages.len());
+        if let Some(backup) = backups(file_path).into_iter().find(|x| x.exists()) {
+            note.push_str(&format!(", the last backup is {}", backup.display()));
+        }
+        Ok((conv, Some(note)))
+    }
+}
+
+/// Lenient reader of json that is cut off or broken somewhere, see [`salvage`].
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    /// Skips whitespace and `separators`, then returns the next byte without consuming it.
+    fn peek(&mut self, separators: &[u8]) -> Option<u8> {
+        while let Some(&x) = self.data.get(self.pos) {
+            if !x.is_ascii_whitespace() && !separators.contains(&x) {
+                return Some(x);
+            }
+            self.pos += 1;
+        }
+        None
+    }
+    /// The value at the position, None when it is incomplete or invalid.
+    fn value(&mut self) -> Option<Value> {
+        let mut values = serde_json::Deserializer::from_slice(&self.data[self.pos..]).into_iter::<Value>();
+        let value = values.next()?.ok()?;
+        self.pos += values.byte_offset();
+        Some(value)
+    }
+    /// Reads the array at the position into `values` up to its first invalid
+    /// element, false when it did not get to the end of it.
+    fn array(&mut self, values: &mut Vec<Value>) -> bool {
+        self.pos += 1;
+        loop {
+            match self.peek(b",") {
+                Some(b']') => {
+                    self.pos += 1;
+                    return true;
+                }
+                Some(_) => match self.value() {
+                    Some(value) => values.push(value),
+                    None => return false,
+                },
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Reads what it can of a conversation file that can't be parsed: the header
+/// fields and the messages up to where the file is broken, skipping the ones that
+/// are invalid. None for files written by a newer version.
+fn salvage(data: &[u8]) -> Option<Conversation> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut header = serde_json::Map::new();
+    let mut messages = Vec::new();
+    let mut branches = Vec::new();
+    let bare = reader.peek(b"") == Some(b'[');
+    match reader.peek(b"") {
+        Some(b'[') => {
+            reader.array(&mut messages);
+        }
+        Some(b'{') => {
+            reader.pos += 1;
+            while reader.peek(b",") == Some(b'"') {
+                let Some(Value::String(key)) = reader.value() else { break };
+                let values = match key.as_str() {
+                    "messages" => &mut messages,
+                    "branches" => &mut branches,
+                    _ => {
+                        reader.peek(b":");
+                        let Some(value) = reader.value() else { break };
+                        header.insert(key, value);
+                        continue;
+                    }
+                };
+                if reader.peek(b":") != Some(b'[') || !reader.array(values) {
+                    break;
+                }
+            }
+        }
+        _ => {}
+    }
+    // NOTE: the version comes first, a file without it has no header left to salvage
+    header.entry("version").or_insert(FORMAT_VERSION.into());
+    header.insert("messages".to_string(), Value::Array(Vec::new()));
+    let mut conv = Conversation::from_json(Value::Object(header)).ok()?;
+    for msg in messages {
+        let one = match bare {
+            true => Value::Array(vec![msg]),
+            false => serde_json::json!({ "version": FORMAT_VERSION, "messages": [msg] }),
+        };
+        let Ok(mut one) = Conversation::from_json(one) else { continue };
+        let Some(mut msg) = one.messages.pop() else { continue };
+        if bare { // NOTE: migrated one by one, they would all get the first id
+            msg.id = conv.next_id();
+        }
+        conv.messages.push(msg);
+    }
+    for node in branches {
+        let one = serde_json::json!({ "version": FORMAT_VERSION, "messages": [], "branches": [node] });
+        conv.branches.extend(Conversation::from_json(one).map(|x| x.branches).unwrap_or_default());
+    }
+    Some(conv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover_truncated_messages() {
+        let dir = std::env::temp_dir().join(format!("gpterm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.json");
+        let mut conv = Conversation { title: Some("rust questions".to_string()), model: Some("gpt-4o".to_string()), ..Default::default() };
+        conv.push(Role::User, "hi".to_string());
+        conv.push(Role::AI, "hello".to_string());
+        conv.fork(1);
+        let reply = conv.push(Role::AI, "hey".to_string());
+        reply.meta.model = Some("gpt-4o".to_string());
+        reply.meta.usage = Some(Usage { prompt_tokens: 9, completion_tokens: 1 });
+        reply.meta.finish_reason = Some(FinishReason::Stop);
+        conv.push(Role::User, "how are you doing today?".to_string());
+        conv.save(&path).unwrap();
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &data[..data.find("doing").unwrap()]).unwrap();
+        let (recovered, note) = Conversation::recover(&path).unwrap();
+        assert!(note.is_some());
+        assert!(with_suffix(&path, ".corrupt").exists());
+        assert_eq!(recovered.title, conv.title);
+        assert_eq!(recovered.model, conv.model);
+        assert_eq!(recovered.messages, conv.messages[..2]);
+        assert_eq!(recovered.branches, conv.branches);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrate_bare_array() {
+        let data = serde_json::json!([["system", "Error: Can't initialize clipboard"], ["user", "hi"], ["assistant", "hello"]]);
+        let conv = Conversation::from_json(data).unwrap();
+        assert_eq!(conv.messages.iter().map(|x| x.role).collect::<Vec<_>>(), [Role::Notice, Role::User, Role::AI]);
+        assert_eq!(conv.messages.iter().map(|x| x.id).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(conv.history().iter().all(|(_, (role, _))| *role != Role::System));
+    }
+
+    #[test]
+    fn salvage_bare_array() {
+        let data = br#"[["user", "hi"], ["assistant", "hello"], ["user", "how are"#;
+        let conv = salvage(data).unwrap();
+        assert_eq!(conv.messages.iter().map(|x| (x.id, x.content.as_str())).collect::<Vec<_>>(), [(1, "hi"), (2, "hello")]);
+    }
+}
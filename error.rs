@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Config,
+    Connect,
+    Tls,
+    Http,
+    RateLimit,
+    Parse,
+    Timeout,
+    Provider,
+    /// The backend thread died without finishing its request.
+    Internal,
+}
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Config => "Configuration error",
+            ErrorKind::Connect => "Connection error",
+            ErrorKind::Tls => "TLS error",
+            ErrorKind::Http => "Request failed",
+            ErrorKind::RateLimit => "Rate limited",
+            ErrorKind::Parse => "Unexpected response",
+            ErrorKind::Timeout => "Timed out",
+            ErrorKind::Provider => "Provider error",
+            ErrorKind::Internal => "Internal error",
+        })
+    }
+}
+
+/// Everything that can go wrong between sending a prompt and the end of its reply.
+/// The messages are meant for the user, so they say what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GptError {
+    /// Missing key, bad url and the like, nothing was sent.
+    Config(String),
+    /// The server could not be reached or the connection broke.
+    Connect(String),
+    Tls(String),
+    /// The server refused the request with `code`, it may say when to try again.
+    Status { code: u16, message: String, retry_after: Option<Duration> },
+    /// 429, the server may say how long to back off.
+    RateLimit { message: String, retry_after: Option<Duration> },
+    /// The response did not look like what the provider is supposed to send.
+    Parse(String),
+    /// The server stopped sending anything.
+    Timeout,
+    /// The provider reported an error in the middle of the reply.
+    Provider(String),
+    Internal(String),
+}
+
+impl GptError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            GptError::Config(_) => ErrorKind::Config,
+            GptError::Connect(_) => ErrorKind::Connect,
+            GptError::Tls(_) => ErrorKind::Tls,
+            GptError::Status { .. } => ErrorKind::Http,
+            GptError::RateLimit { .. } => ErrorKind::RateLimit,
+            GptError::Parse(_) => ErrorKind::Parse,
+            GptError::Timeout => ErrorKind::Timeout,
+            GptError::Provider(_) => ErrorKind::Provider,
+            GptError::Internal(_) => ErrorKind::Internal,
+        }
+    }
+    /// Whether sending the same request again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            GptError::Connect(_) | GptError::Timeout | GptError::RateLimit { .. } => true,
+            // NOTE: 529 is anthropic's "overloaded"
+            GptError::Status { code, .. } => matches!(code, 408 | 409 | 500..=599),
+            _ => false,
+        }
+    }
+    /// How long the server asked us to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            GptError::Status { retry_after, .. } | GptError::RateLimit { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+    /// `key` is an environment variable the backend can not do without.
+    pub fn missing_env(key: &str) -> Self {
+        GptError::Config(format!("{key} is not set, export it and restart gpterm"))
+    }
+}
+
+impl std::fmt::Display for GptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GptError::Config(msg) => write!(f, "{msg}"),
+            GptError::Connect(msg) => write!(f, "{msg}. Check the url and your network connection"),
+            GptError::Tls(msg) => write!(f, "{msg}. Check that the server speaks https and its certificate is trusted"),
+            GptError::Status { code, message, .. } => {
+                let hint = match code {
+                    401 | 403 => "check your API key",
+                    404 => "check the url and the model name",
+                    400 | 413 | 422 => "the request was rejected, the conversation may be too long",
+                    500..=599 => "the server is having trouble, try again later",
+                    _ => "",
+                };
+                if hint.is_empty() {
+                    write!(f, "{code}: {message}")
+                } else {
+                    write!(f, "{code}: {message} ({hint})")
+                }
+            }
+            GptError::RateLimit { message, retry_after: Some(after) } => {
+                write!(f, "{message}, try again in {}s", after.as_secs().max(1))
+            }
+            GptError::RateLimit { message, retry_after: None } => write!(f, "{message}, wait a bit and try again"),
+            GptError::Parse(msg) => write!(f, "{msg}"),
+            GptError::Timeout => write!(f, "The server stopped answering, try again"),
+            GptError::Provider(msg) => write!(f, "{msg}"),
+            GptError::Internal(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for GptError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => GptError::Timeout,
+            _ => GptError::Connect(err.to_string()),
+        }
+    }
+}
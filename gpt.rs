@@ -1,117 +1,184 @@
-use openssl::ssl::{SslMethod, SslConnector};
-use std::sync::mpsc::{Sender};
-use std::net::TcpStream;
-use std::io::{Write, Read};
-use std::collections::HashMap;
 use std::time::Duration;
 use serde_json::Value;
+use crate::Role;
+use crate::backend::{ChatBackend, ChatRequest, CancelHandle, FinishReason, Sink, StreamEvent, Usage};
+use crate::error::GptError;
+use crate::http::{self, Endpoint};
+use crate::sse;
 
 const CHAT_MODEL: &str = "gpt-3.5-turbo";
+const OPENAI_URL: &str = "https://api.openai.com/v1";
+// NOTE: stream_options needs at least 2024-09-01-preview
+const AZURE_API_VERSION: &str = "2024-10-21";
 
-fn make_prompt(conversation: &Value) -> String {
-    let secret = std::env::var("GPT_SECRET_KEY").unwrap();
-    let body = serde_json::json!({
-        "model": CHAT_MODEL,
-        "messages": conversation,
-        "stream": true
-    }).to_string();
-    return format!("POST /v1/chat/completions HTTP/1.1\r\nHost: api.openai.com\r\nContent-Length: {}\r\nContent-Type: application/json\r\nAuthorization: Bearer {secret}\r\n\r\n{}",
-        body.len(), body);
+/// Extracts the content deltas, finish reason and usage of a chat completions
+/// event, the stream ends with a `[DONE]` sentinel that carries none. Usage comes
+/// in a last event with no choices, as asked for with `stream_options.include_usage`.
+pub fn on_parse_event(event: &sse::Event) -> Vec<StreamEvent> {
+    if event.data == "[DONE]" {
+        return Vec::new();
+    }
+    let data = match serde_json::from_str::<Value>(&event.data) {
+        Ok(data) => data,
+        Err(_err) => return vec![GptError::Parse(format!("Could not parse {}", event.data)).into()],
+    };
+    if let Some(msg) = data["error"]["message"].as_str() {
+        return vec![GptError::Provider(msg.to_string()).into()];
+    }
+    let mut events = Vec::new();
+    for choice in data["choices"].as_array().into_iter().flatten() {
+        if let Some(text) = choice["delta"]["content"].as_str() {
+            events.push(StreamEvent::Delta { text: text.to_string() });
+        }
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            events.push(StreamEvent::Finished { reason: FinishReason::from(reason) });
+        }
+    }
+    if let (Some(prompt), Some(completion)) = (data["usage"]["prompt_tokens"].as_u64(), data["usage"]["completion_tokens"].as_u64()) {
+        events.push(StreamEvent::Usage(Usage { prompt_tokens: prompt, completion_tokens: completion }));
+    }
+    events
+}
+
+/// Decodes a chat completions event stream, see [`on_parse_event`].
+pub fn on_parse_body(decoder: &mut sse::Decoder, data: &[u8]) -> Vec<StreamEvent> {
+    decoder.feed(data).iter().flat_map(on_parse_event).collect()
 }
-fn on_parse_header(data: &str, headers: &mut HashMap<String, String>) {
-    if let Some(sep) = data.find(": ") {
-        headers.insert(data[..sep].to_string(), data[sep+2..].to_string());
-    } else {
-        let tokens: Vec<_> = data.splitn(3, " ").collect();
-        headers.insert("protocol".to_string(), tokens[0].to_string());
-        headers.insert("status".to_string(), tokens[1].to_string());
-        headers.insert("status-msg".to_string(), tokens[2].to_string());
+
+/// Ids listed by a `/models` endpoint, `{"data": [{"id": ..}, ..]}`.
+pub fn model_ids(models: &Value) -> Vec<String> {
+    let mut ids: Vec<String> = models["data"].as_array().into_iter().flatten()
+        .filter_map(|x| x["id"].as_str())
+        .map(|x| x.to_string())
+        .collect();
+    ids.sort();
+    ids
+}
+
+pub fn to_messages(conv: &[(Role, String)]) -> Value {
+    conv.iter().map(|(role, content)| serde_json::json!({
+        "role": role.value(),
+        "content": content,
+    })).collect()
+}
+
+/// Copies the fields of `params` into the request `body`.
+pub fn add_params(body: &mut Value, params: &Value) {
+    for (name, value) in params.as_object().into_iter().flatten() {
+        body[name] = value.clone();
     }
 }
 
-fn on_parse_body(data: &str) -> String {
-    if data.starts_with("data") {
-        let msg = data.split("\n\n").filter(|x| !x.is_empty()).map(|x| {
-            if let Ok(data) = serde_json::from_str::<Value>(&x[6..]) {
-                data
-            } else {
-                Value::Null
-            }
-        }).filter(|x| x != &Value::Null).fold(String::new(), |mut acc, x| {
-            for msg in x["choices"].as_array().unwrap().iter() {
-                if let Some(str) = msg["delta"]["content"].as_str() {
-                    acc += str;
-                }
-            }
-            acc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Auth {
+    /// `Authorization: Bearer <key>`, openai and most compatible servers.
+    Bearer,
+    /// `api-key: <key>`, Azure OpenAI.
+    ApiKey,
+}
+
+/// Any server speaking the openai chat completions API: api.openai.com, Azure OpenAI,
+/// OpenRouter, vLLM, an internal gateway...
+pub struct OpenAi {
+    endpoint: Endpoint,
+    auth: Auth,
+    /// Environment variable holding the key.
+    key_var: &'static str,
+    /// Whether the server refuses requests without a key, a local or self hosted
+    /// server may not need one so requests go out without it.
+    key_required: bool,
+}
+impl OpenAi {
+    /// api.openai.com, or whatever OPENAI_BASE_URL points to, keyed by GPT_SECRET_KEY
+    /// which only api.openai.com insists on.
+    pub fn new() -> Result<Self, String> {
+        let url = std::env::var("OPENAI_BASE_URL").unwrap_or(OPENAI_URL.to_string());
+        let endpoint = Endpoint::parse(&url)?;
+        let key_required = url.trim_end_matches('/') == OPENAI_URL;
+        Ok(OpenAi { endpoint, auth: Auth::Bearer, key_var: "GPT_SECRET_KEY", key_required })
+    }
+    /// Azure OpenAI deployment at AZURE_OPENAI_ENDPOINT
+    /// (`https://<resource>.openai.azure.com/openai/deployments/<deployment>`),
+    /// keyed by AZURE_OPENAI_API_KEY, AZURE_OPENAI_API_VERSION sets the api-version.
+    pub fn azure() -> Result<Self, String> {
+        let url = std::env::var("AZURE_OPENAI_ENDPOINT")
+            .map_err(|_err| GptError::missing_env("AZURE_OPENAI_ENDPOINT").to_string())?;
+        let version = std::env::var("AZURE_OPENAI_API_VERSION").unwrap_or(AZURE_API_VERSION.to_string());
+        let endpoint = Endpoint::parse(&url)?.with_query_default("api-version", &version);
+        Ok(OpenAi { endpoint, auth: Auth::ApiKey, key_var: "AZURE_OPENAI_API_KEY", key_required: true })
+    }
+    fn headers(&self) -> Result<Vec<(&'static str, String)>, GptError> {
+        match (std::env::var(self.key_var), self.auth) {
+            (Ok(secret), Auth::Bearer) => Ok(vec![("Authorization", format!("Bearer {secret}"))]),
+            (Ok(secret), Auth::ApiKey) => Ok(vec![("api-key", secret)]),
+            (Err(_err), _) if self.key_required => Err(GptError::missing_env(self.key_var)),
+            (Err(_err), _) => Ok(vec![]),
+        }
+    }
+    fn make_prompt(&self, req: &ChatRequest) -> Result<String, GptError> {
+        let mut body = serde_json::json!({
+            "model": req.model,
+            "messages": to_messages(&req.messages),
+            "stream": true,
+            "stream_options": {"include_usage": true}
         });
-        return msg;
-    } else {
-        return String::new();
+        add_params(&mut body, &req.params.to_json());
+        Ok(self.endpoint.make_post("/chat/completions", &self.headers()?, &body.to_string()))
     }
 }
 
-pub fn prompt(conv: &Value, tx: Sender<String>) {
-    let req = make_prompt(conv);
-    let builder = SslConnector::builder(SslMethod::tls()).unwrap();
-    // builder.set_ca_file("/etc/ssl/certs/ca-certificates.crt").unwrap();
-    let connector = builder.build();
-    let stream = TcpStream::connect("api.openai.com:443").unwrap();
-    TcpStream::set_read_timeout(&stream, Some(Duration::from_secs(10))).unwrap();
-    let mut stream = connector.connect("api.openai.com", stream).unwrap();
-    // let mut headers: HashMap<String, String> = HashMap::new();
-    stream.write(req.as_bytes()).unwrap();
-    const BUFFER_SIZE: usize = 1024;
-    let buffer: &mut [u8] = &mut [0; BUFFER_SIZE];
-    let mut response: String = String::new();
-    let mut index: usize = 0;
-    let mut headers: HashMap<String, String> = HashMap::new();
-    let mut is_parsing_header = true;
-
-    'outer: loop {
-        match stream.read(buffer) {
-            Ok(n) => {
-                let chunk = String::from_utf8(buffer[..n].to_vec()).unwrap();
-                response.push_str(&chunk);
-                loop { // NOTE: loop over all chunk of data seperated by \r\n
-                    if let Some(i) = response[index..].find("\r\n") {
-                        if i <= 0 { // NOTE: end of header or body
-                            if is_parsing_header { // NOTE: end of header
-                                index += i+2;
-                                is_parsing_header = false;
-                                if headers.get("status").unwrap() != "200" {
-                                    tx.send("[START] system".to_string()).unwrap();
-                                    let rest = &response[index+i..];
-                                    let msg = match serde_json::from_str::<Value>(rest) {
-                                        Ok(error) => error["error"]["message"].as_str().unwrap().to_string(),
-                                        Err(_err) => format!("Could not parse {rest}"),
-                                    };
-                                    tx.send(msg).unwrap();
-                                    break 'outer;
-                                } else {
-                                    tx.send("[START] assistant".to_string()).unwrap();
-                                }
-                                continue;
-                            } else { // NOTE: end of body
-                                break 'outer;
-                            }
-                        }
-                        let data = &response[index..index+i].trim();
-                        if is_parsing_header {
-                            on_parse_header(data, &mut headers);
-                        } else {
-                            tx.send(on_parse_body(data)).unwrap();
-                        }
-                        index += i+2;
-                    } else { break; }
-                }
-            }
-            Err(err) => {
-                tx.send("[START] system".to_string()).unwrap();
-                tx.send(err.to_string()).unwrap();
-                break;
-            }
+impl ChatBackend for OpenAi {
+    fn default_model(&self) -> &str {
+        CHAT_MODEL
+    }
+    fn prompt(&self, req: &ChatRequest, sink: &Sink, cancel: &CancelHandle) -> Result<(), GptError> {
+        let req = self.make_prompt(req)?;
+        let mut stream = self.endpoint.connect(Duration::from_secs(10), cancel)?;
+        let mut decoder = sse::Decoder::default();
+        http::post_streaming(&mut stream, &req, sink, cancel, |data| on_parse_body(&mut decoder, data))
+    }
+    fn list_models(&self) -> Result<Vec<String>, GptError> {
+        if self.auth == Auth::ApiKey { // NOTE: the azure deployment decides the model
+            return Ok(Vec::new());
         }
+        let req = self.endpoint.make_get("/models", &self.headers()?);
+        let mut stream = self.endpoint.connect(Duration::from_secs(10), &CancelHandle::new())?;
+        let models = http::fetch_json(&mut stream, &req)?;
+        Ok(model_ids(&models))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE: recorded from the API with stream_options.include_usage, trimmed
+    const STREAM: &str = "data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4o-mini\",\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\",\"refusal\":null},\"logprobs\":null,\"finish_reason\":null}],\"usage\":null}\n\n\
+data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4o-mini\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"},\"logprobs\":null,\"finish_reason\":null}],\"usage\":null}\n\n\
+data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4o-mini\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\" wörld\"},\"logprobs\":null,\"finish_reason\":null}],\"usage\":null}\n\n\
+data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4o-mini\",\"choices\":[{\"index\":0,\"delta\":{},\"logprobs\":null,\"finish_reason\":\"length\"}],\"usage\":null}\n\n\
+data: {\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"model\":\"gpt-4o-mini\",\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":2,\"total_tokens\":11}}\n\n\
+data: [DONE]\n\n";
+
+    #[test]
+    fn parse_stream() {
+        let mut decoder = sse::Decoder::default();
+        let events: Vec<StreamEvent> = STREAM.as_bytes().chunks(7).flat_map(|x| on_parse_body(&mut decoder, x)).collect();
+        assert_eq!(events, [
+            StreamEvent::Delta { text: String::new() },
+            StreamEvent::Delta { text: "Hello".to_string() },
+            StreamEvent::Delta { text: " wörld".to_string() },
+            StreamEvent::Finished { reason: FinishReason::Length },
+            StreamEvent::Usage(Usage { prompt_tokens: 9, completion_tokens: 2 }),
+        ]);
+    }
+
+    #[test]
+    fn parse_error_event() {
+        let mut decoder = sse::Decoder::default();
+        let events = on_parse_body(&mut decoder, b"data: {\"error\": {\"message\": \"The server had an error\", \"type\": \"server_error\"}}\n\n");
+        assert!(matches!(&events[..], [StreamEvent::Error { message, .. }] if message.contains("server had an error")), "{events:?}");
+        let events = on_parse_body(&mut decoder, b"data: {\"choices\": [\n\n");
+        assert!(matches!(&events[..], [StreamEvent::Error { .. }]), "{events:?}");
     }
-    tx.send("[DONE]".to_string()).unwrap();
 }
@@ -0,0 +1,851 @@
+use openssl::ssl::{SslMethod, SslConnector, SslStream};
+use std::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::collections::HashMap;
+use std::time::Duration;
+use serde_json::Value;
+use crate::Role;
+use crate::backend::{CancelHandle, FinishReason, Sink, StreamEvent};
+use crate::error::GptError;
+
+/// A socket to the provider, either plain TCP (local servers) or TLS.
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Where a backend sends its requests, parsed from a url like
+/// `https://example.openai.azure.com/openai/deployments/gpt4?api-version=2024-06-01`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// Path prefix without trailing slash, requests append their own route to it.
+    pub path: String,
+    pub query: Option<String>,
+}
+impl Endpoint {
+    /// Parses `url`, which defaults to plain http when it has no scheme.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        Self::parse_with_port(url, 80)
+    }
+    /// Like [`Endpoint::parse`], but a url with neither scheme nor port gets `port`,
+    /// the way local servers read their host variables.
+    pub fn parse_with_port(url: &str, port: u16) -> Result<Self, String> {
+        let url = url.trim();
+        let (tls, rest, default_port) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest, 443)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest, 80)
+        } else if url.contains("://") {
+            return Err(format!("Unsupported scheme in {url}, expected http or https"));
+        } else {
+            (false, url, port)
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query.to_string())),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = if let Some(host) = authority.strip_prefix('[') { // NOTE: ipv6 [::1]:port
+            match host.split_once(']') {
+                Some((host, "")) => (host, default_port),
+                Some((host, port)) => (host, Self::parse_port(port.trim_start_matches(':'), url)?),
+                None => return Err(format!("Invalid host in {url}")),
+            }
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Self::parse_port(port, url)?),
+                None => (authority, default_port),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in {url}"));
+        }
+        Ok(Endpoint { tls, host: host.to_string(), port, path: path.to_string(), query })
+    }
+    fn parse_port(port: &str, url: &str) -> Result<u16, String> {
+        port.parse().map_err(|_err| format!("Invalid port in {url}"))
+    }
+    /// Value of the Host header, the port is left out when it is the scheme's default.
+    pub fn host_header(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        if self.port == if self.tls { 443 } else { 80 } {
+            host
+        } else {
+            format!("{host}:{}", self.port)
+        }
+    }
+    /// Request target for `route` under this endpoint's path, with its query string.
+    pub fn target(&self, route: &str) -> String {
+        match &self.query {
+            Some(query) => format!("{}{route}?{query}", self.path),
+            None => format!("{}{route}", self.path),
+        }
+    }
+    /// Adds `key=value` to the query string unless `key` is already set.
+    pub fn with_query_default(mut self, key: &str, value: &str) -> Self {
+        let is_set = self.query.as_deref().unwrap_or_default().split('&')
+            .any(|x| x.split('=').next() == Some(key));
+        if !is_set {
+            self.query = Some(match self.query {
+                Some(query) if !query.is_empty() => format!("{query}&{key}={value}"),
+                _ => format!("{key}={value}"),
+            });
+        }
+        self
+    }
+    pub fn connect(&self, timeout: Duration, cancel: &CancelHandle) -> Result<Connection, GptError> {
+        connect(&self.host, self.port, self.tls, timeout, cancel)
+    }
+    /// Builds a POST of `body` to `route`, see [`make_request`].
+    pub fn make_post(&self, route: &str, headers: &[(&str, String)], body: &str) -> String {
+        make_request("POST", &self.host_header(), &self.target(route), headers, Some(body))
+    }
+    /// Builds a GET of `route`, see [`make_request`].
+    pub fn make_get(&self, route: &str, headers: &[(&str, String)]) -> String {
+        make_request("GET", &self.host_header(), &self.target(route), headers, None)
+    }
+}
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}{}", if self.tls { "https" } else { "http" }, self.host_header(), self.path)
+    }
+}
+
+/// Opens a connection whose socket is shut down when `cancel` is triggered.
+pub fn connect(host: &str, port: u16, tls: bool, timeout: Duration, cancel: &CancelHandle) -> Result<Connection, GptError> {
+    let stream = TcpStream::connect((host, port))
+        .map_err(|err| GptError::Connect(format!("Could not connect to {host}:{port}: {err}")))?;
+    stream.set_read_timeout(Some(timeout))?;
+    cancel.watch(&stream);
+    if !tls {
+        return Ok(Connection::Plain(stream));
+    }
+    let builder = SslConnector::builder(SslMethod::tls())
+        .map_err(|err| GptError::Tls(format!("Could not set up TLS: {err}")))?;
+    // builder.set_ca_file("/etc/ssl/certs/ca-certificates.crt").unwrap();
+    let connector = builder.build();
+    connector.connect(host, stream)
+        .map(Connection::Tls)
+        .map_err(|err| GptError::Tls(format!("TLS handshake with {host} failed: {err}")))
+}
+
+/// Builds a request with an optional json body, `headers` are added as is.
+pub fn make_request(method: &str, host: &str, path: &str, headers: &[(&str, String)], body: Option<&str>) -> String {
+    let mut req = format!("{method} {path} HTTP/1.1\r\nHost: {host}\r\n");
+    if let Some(body) = body {
+        req.push_str(&format!("Content-Length: {}\r\nContent-Type: application/json\r\n", body.len()));
+    }
+    for (name, value) in headers {
+        req.push_str(&format!("{name}: {value}\r\n"));
+    }
+    req.push_str("\r\n");
+    req.push_str(body.unwrap_or_default());
+    req
+}
+
+/// Status line and headers of a response, header names are lowercased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Head {
+    pub status: u16,
+    pub reason: String,
+    pub headers: HashMap<String, String>,
+}
+impl Head {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|x| x.as_str())
+    }
+}
+
+fn on_parse_status(data: &str) -> Result<Head, String> {
+    let tokens: Vec<_> = data.splitn(3, ' ').collect();
+    if tokens.len() < 2 || !tokens[0].starts_with("HTTP/") {
+        return Err(format!("Invalid status line {data:?}"));
+    }
+    let status = tokens[1].parse().map_err(|_err| format!("Invalid status code in {data:?}"))?;
+    let reason = tokens.get(2).unwrap_or(&"").to_string();
+    Ok(Head { status, reason, headers: HashMap::new() })
+}
+
+fn on_parse_header(data: &str, headers: &mut HashMap<String, String>) -> Result<(), String> {
+    let (name, value) = data.split_once(':').ok_or(format!("Invalid header {data:?}"))?;
+    let name = name.trim().to_lowercase();
+    let value = value.trim();
+    match headers.get_mut(&name) { // NOTE: repeated headers are combined into a list
+        Some(prev) => {
+            prev.push_str(", ");
+            prev.push_str(value);
+        }
+        None => {
+            headers.insert(name, value.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Removes the next line from `buf` without its line ending, `None` if it is not complete yet.
+fn take_line(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let end = buf.iter().position(|x| *x == b'\n')?;
+    let mut line: Vec<u8> = buf.drain(..=end).collect();
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Some(line)
+}
+
+/// Removes up to `remaining` bytes from the front of `buf`.
+fn take_body(buf: &mut Vec<u8>, remaining: &mut usize) -> Vec<u8> {
+    let n = buf.len().min(*remaining);
+    *remaining -= n;
+    buf.drain(..n).collect()
+}
+
+/// A piece of a response, in the order they come off the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    Head(Head),
+    /// Decoded body bytes, chunk framing already stripped.
+    Body(Vec<u8>),
+    /// The body is complete, with the trailer fields of a chunked body if any.
+    End(HashMap<String, String>),
+}
+
+#[derive(Debug)]
+enum ParseState {
+    Status,
+    Headers(Head),
+    Length(usize),
+    UntilClose,
+    ChunkSize,
+    ChunkData(usize),
+    ChunkEnd,
+    Trailers(HashMap<String, String>),
+    Done,
+}
+
+/// Incremental HTTP/1.1 response parser: feed it whatever the socket returns and
+/// it hands back the parts completed so far, no matter where the reads split.
+pub struct ResponseParser {
+    buf: Vec<u8>,
+    state: ParseState,
+}
+impl ResponseParser {
+    pub fn new() -> Self {
+        ResponseParser { buf: Vec::new(), state: ParseState::Status }
+    }
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, ParseState::Done)
+    }
+    fn body_state(head: &Head) -> Result<ParseState, String> {
+        if head.status == 204 || head.status == 304 {
+            Ok(ParseState::Length(0))
+        } else if head.header("transfer-encoding").is_some_and(|x| x.to_lowercase().contains("chunked")) {
+            Ok(ParseState::ChunkSize)
+        } else if let Some(len) = head.header("content-length") {
+            len.trim().parse().map(ParseState::Length).map_err(|_err| format!("Invalid Content-Length {len:?}"))
+        } else {
+            Ok(ParseState::UntilClose)
+        }
+    }
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Part>, String> {
+        self.buf.extend_from_slice(data);
+        let mut parts = Vec::new();
+        loop {
+            match &mut self.state {
+                ParseState::Status => {
+                    let Some(line) = take_line(&mut self.buf) else { break };
+                    if !line.is_empty() { // NOTE: tolerate stray empty lines before the status line
+                        self.state = ParseState::Headers(on_parse_status(&String::from_utf8_lossy(&line))?);
+                    }
+                }
+                ParseState::Headers(head) => {
+                    let Some(line) = take_line(&mut self.buf) else { break };
+                    if !line.is_empty() {
+                        on_parse_header(&String::from_utf8_lossy(&line), &mut head.headers)?;
+                    } else if (100..200).contains(&head.status) { // NOTE: interim response, the real one follows
+                        self.state = ParseState::Status;
+                    } else {
+                        let head = head.clone();
+                        self.state = Self::body_state(&head)?;
+                        parts.push(Part::Head(head));
+                    }
+                }
+                ParseState::Length(0) => {
+                    self.state = ParseState::Done;
+                    parts.push(Part::End(HashMap::new()));
+                }
+                ParseState::Length(remaining) | ParseState::ChunkData(remaining) => {
+                    if self.buf.is_empty() { break; }
+                    parts.push(Part::Body(take_body(&mut self.buf, remaining)));
+                    if let ParseState::ChunkData(0) = self.state {
+                        self.state = ParseState::ChunkEnd;
+                    }
+                }
+                ParseState::UntilClose => {
+                    if self.buf.is_empty() { break; }
+                    parts.push(Part::Body(std::mem::take(&mut self.buf)));
+                }
+                ParseState::ChunkSize => {
+                    let Some(line) = take_line(&mut self.buf) else { break };
+                    let line = String::from_utf8_lossy(&line);
+                    let size = line.split(';').next().unwrap_or_default().trim(); // NOTE: drop chunk extensions
+                    let size = usize::from_str_radix(size, 16).map_err(|_err| format!("Invalid chunk size {line:?}"))?;
+                    self.state = if size == 0 { ParseState::Trailers(HashMap::new()) } else { ParseState::ChunkData(size) };
+                }
+                ParseState::ChunkEnd => {
+                    let Some(line) = take_line(&mut self.buf) else { break };
+                    if !line.is_empty() {
+                        return Err("Missing line break after chunk data".to_string());
+                    }
+                    self.state = ParseState::ChunkSize;
+                }
+                ParseState::Trailers(trailers) => {
+                    let Some(line) = take_line(&mut self.buf) else { break };
+                    if !line.is_empty() {
+                        on_parse_header(&String::from_utf8_lossy(&line), trailers)?;
+                    } else {
+                        parts.push(Part::End(std::mem::take(trailers)));
+                        self.state = ParseState::Done;
+                    }
+                }
+                ParseState::Done => break,
+            }
+        }
+        Ok(parts)
+    }
+    /// To be called once the server closed the connection.
+    pub fn finish(&mut self) -> Result<Vec<Part>, String> {
+        match self.state {
+            ParseState::Done => Ok(Vec::new()),
+            ParseState::UntilClose => {
+                self.state = ParseState::Done;
+                Ok(vec![Part::End(HashMap::new())])
+            }
+            _ => Err("Connection closed before the response was complete".to_string()),
+        }
+    }
+}
+
+/// Splits a body into lines, holding back the unterminated last line until the rest
+/// of it arrives so a read never cuts through a line or a multibyte character.
+/// For newline delimited json, event streams go through [`crate::sse::Decoder`].
+#[derive(Default)]
+pub struct LineBuffer {
+    buf: Vec<u8>,
+}
+impl LineBuffer {
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(data);
+        let mut lines = Vec::new();
+        while let Some(line) = take_line(&mut self.buf) {
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+        lines
+    }
+}
+
+/// Pulls the message out of an error body, openai style `{"error": {"message": ..}}`
+/// or ollama style `{"error": ".."}`.
+fn error_message(body: &str) -> String {
+    match serde_json::from_str::<Value>(body) {
+        Ok(error) => match &error["error"] {
+            Value::String(msg) => msg.clone(),
+            err => match err["message"].as_str() {
+                Some(msg) => msg.to_string(),
+                None => format!("Unexpected error {body}"),
+            },
+        },
+        Err(_err) => format!("Could not parse {body}"),
+    }
+}
+
+/// Parses durations like `1s`, `6m0s`, `20ms` or `1h2m3.5s`, the format of openai's
+/// `x-ratelimit-reset-*` headers. A bare number counts as seconds.
+fn parse_duration(data: &str) -> Option<Duration> {
+    let data = data.trim();
+    if let Ok(secs) = data.parse::<f64>() {
+        return Duration::try_from_secs_f64(secs).ok();
+    }
+    let mut total = 0.0;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let end = rest.find(|x: char| !x.is_ascii_digit() && x != '.')?;
+        let value: f64 = rest[..end].parse().ok()?;
+        rest = &rest[end..];
+        let unit_end = rest.find(|x: char| x.is_ascii_digit()).unwrap_or(rest.len());
+        total += value * match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+/// Seconds since the unix epoch of an IMF-fixdate like `Wed, 21 Oct 2015 07:28:00 GMT`.
+fn parse_http_date(data: &str) -> Option<u64> {
+    let tokens: Vec<_> = data.split_whitespace().collect();
+    let [_weekday, day, month, year, time, "GMT"] = tokens[..] else { return None };
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = MONTHS.iter().position(|x| *x == month)? as i64 + 1;
+    let (day, year): (i64, i64) = (day.parse().ok()?, year.parse().ok()?);
+    let time: Vec<i64> = time.split(':').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    let [hour, min, sec] = time[..] else { return None };
+    // NOTE: days from civil, http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + hour * 3600 + min * 60 + sec).ok()
+}
+
+/// How long the server wants us to wait, from `retry-after-ms`, `retry-after`
+/// (seconds or a date) or, for a 429, the `x-ratelimit-reset-*` headers of the
+/// limits that are used up.
+fn retry_after(head: &Head) -> Option<Duration> {
+    if let Some(ms) = head.header("retry-after-ms").and_then(|x| x.trim().parse::<u64>().ok()) {
+        return Some(Duration::from_millis(ms));
+    }
+    if let Some(value) = head.header("retry-after") {
+        if let Ok(secs) = value.trim().parse::<u64>() {
+            return Some(Duration::from_secs(secs));
+        }
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).ok()?;
+        return parse_http_date(value).map(|at| Duration::from_secs(at.saturating_sub(now.as_secs())));
+    }
+    if head.status != 429 { // NOTE: openai sends them with every response, they say nothing about errors
+        return None;
+    }
+    head.headers.iter()
+        .filter_map(|(name, value)| Some((name.strip_prefix("x-ratelimit-reset-")?, value)))
+        .filter(|(limit, _)| head.header(&format!("x-ratelimit-remaining-{limit}")).is_some_and(|x| x.trim() == "0"))
+        .filter_map(|(_, value)| parse_duration(value))
+        .max()
+}
+
+/// Turns a refused request into an error, keeping what the server said about
+/// when to come back.
+fn status_error(head: &Head, body: &[u8]) -> GptError {
+    let message = error_message(&String::from_utf8_lossy(body));
+    let retry_after = retry_after(head);
+    if head.status == 429 {
+        GptError::RateLimit { message, retry_after }
+    } else {
+        GptError::Status { code: head.status, message, retry_after }
+    }
+}
+
+/// Writes `req` and reads the whole response, returning its body parsed as json.
+pub fn fetch_json(stream: &mut (impl Read + Write), req: &str) -> Result<Value, GptError> {
+    stream.write_all(req.as_bytes())?;
+    const BUFFER_SIZE: usize = 1024;
+    let buffer: &mut [u8] = &mut [0; BUFFER_SIZE];
+    let mut parser = ResponseParser::new();
+    let mut head: Option<Head> = None;
+    let mut body = Vec::new();
+    while !parser.is_done() {
+        let parts = match stream.read(buffer)? {
+            0 => parser.finish(),
+            n => parser.feed(&buffer[..n]),
+        };
+        for part in parts.map_err(GptError::Parse)? {
+            match part {
+                Part::Head(x) => head = Some(x),
+                Part::Body(data) => body.extend_from_slice(&data),
+                Part::End(_trailers) => {}
+            }
+        }
+    }
+    match head {
+        Some(head) if head.status != 200 => Err(status_error(&head, &body)),
+        _ => serde_json::from_slice(&body)
+            .map_err(|_err| GptError::Parse(format!("Could not parse {}", String::from_utf8_lossy(&body)))),
+    }
+}
+
+/// Writes `req` and streams the response, see [`stream_response`].
+pub fn post_streaming(stream: &mut (impl Read + Write), req: &str, sink: &Sink, cancel: &CancelHandle,
+                      on_body: impl FnMut(&[u8]) -> Vec<StreamEvent>) -> Result<(), GptError> {
+    stream.write_all(req.as_bytes())?;
+    stream_response(stream, sink, cancel, on_body)
+}
+
+/// Reads the response to a streaming request, starts the reply when the server
+/// accepts it and forwards the events `on_body` extracts from the decoded body
+/// bytes, it is up to `on_body` to buffer incomplete lines or events.
+/// A `Finished` from `on_body` is held back until the body is complete so it stays
+/// the last event, failures are returned without finishing the request.
+pub fn stream_response(stream: &mut impl Read, sink: &Sink, cancel: &CancelHandle,
+                       mut on_body: impl FnMut(&[u8]) -> Vec<StreamEvent>) -> Result<(), GptError> {
+    const BUFFER_SIZE: usize = 1024;
+    let buffer: &mut [u8] = &mut [0; BUFFER_SIZE];
+    let mut parser = ResponseParser::new();
+    let mut error: Option<(Head, Vec<u8>)> = None;
+    let mut finish = FinishReason::Stop;
+
+    while !parser.is_done() && !cancel.is_cancelled() {
+        let read = stream.read(buffer);
+        if cancel.is_cancelled() { // NOTE: the socket was shut down under us
+            break;
+        }
+        let parts = match read {
+            Ok(0) => parser.finish(),
+            Ok(n) => parser.feed(&buffer[..n]),
+            Err(err) => return Err(err.into()),
+        };
+        let parts = parts.map_err(GptError::Parse)?;
+        for part in parts {
+            match (part, &mut error) {
+                (Part::Head(head), _) if head.status != 200 => error = Some((head, Vec::new())),
+                (Part::Head(_head), _) => sink.start(Role::AI),
+                (Part::Body(data), Some((_head, body))) => body.extend_from_slice(&data),
+                (Part::Body(data), None) => {
+                    for event in on_body(&data) {
+                        match event {
+                            StreamEvent::Finished { reason } => finish = reason,
+                            StreamEvent::Error { .. } => {
+                                finish = FinishReason::Error;
+                                sink.send(event);
+                            }
+                            StreamEvent::Delta { ref text } if text.is_empty() => {}
+                            event => sink.send(event),
+                        }
+                    }
+                }
+                (Part::End(_trailers), Some((head, body))) => return Err(status_error(head, body)),
+                (Part::End(_trailers), None) => {}
+            }
+        }
+    }
+    if cancel.is_cancelled() {
+        finish = FinishReason::Cancelled;
+    }
+    sink.finish(finish);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKED: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/event-stream\r\n\r\n\
+        8;name=value\r\nHello w\xC3\r\n4\r\n\xB6rld\r\n0\r\nX-Request-Id: 42\r\nX-Request-Id: 43\r\n\r\n";
+    const LENGTH: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\nHello w\xC3\xB6rld";
+
+    /// Status codes, body and trailers of a response.
+    type Parsed = (Vec<u16>, Vec<u8>, HashMap<String, String>);
+
+    /// Feeds `pieces` in order and closes the connection.
+    fn parse(pieces: &[&[u8]]) -> Result<Parsed, String> {
+        let mut parser = ResponseParser::new();
+        let mut parts = Vec::new();
+        for piece in pieces {
+            parts.extend(parser.feed(piece)?);
+        }
+        parts.extend(parser.finish()?);
+        let mut status = Vec::new();
+        let mut body = Vec::new();
+        let mut trailers = None;
+        for part in parts {
+            assert!(trailers.is_none(), "{part:?} after the end");
+            match part {
+                Part::Head(head) => status.push(head.status),
+                Part::Body(data) => body.extend_from_slice(&data),
+                Part::End(x) => trailers = Some(x),
+            }
+        }
+        Ok((status, body, trailers.expect("no end")))
+    }
+
+    #[test]
+    fn take_line_and_body() {
+        let mut buf = b"one\r\ntwo\nthr".to_vec();
+        assert_eq!(take_line(&mut buf), Some(b"one".to_vec()));
+        assert_eq!(take_line(&mut buf), Some(b"two".to_vec()));
+        assert_eq!(take_line(&mut buf), None);
+        assert_eq!(buf, b"thr");
+        let mut remaining = 2;
+        assert_eq!(take_body(&mut buf, &mut remaining), b"th");
+        assert_eq!((remaining, buf.as_slice()), (0, b"r".as_slice()));
+        let mut remaining = 5;
+        assert_eq!(take_body(&mut buf, &mut remaining), b"r");
+        assert_eq!(remaining, 4);
+    }
+
+    #[test]
+    fn chunked_split_anywhere() {
+        let trailers = HashMap::from([("x-request-id".to_string(), "42, 43".to_string())]);
+        for i in 0..=CHUNKED.len() {
+            let (status, body, x) = parse(&[&CHUNKED[..i], &CHUNKED[i..]]).unwrap();
+            assert_eq!(status, [200], "split at {i}");
+            assert_eq!(String::from_utf8(body).unwrap(), "Hello wörld", "split at {i}");
+            assert_eq!(x, trailers, "split at {i}");
+        }
+        let bytes: Vec<&[u8]> = CHUNKED.chunks(1).collect();
+        assert_eq!(parse(&bytes).unwrap().1, "Hello wörld".as_bytes());
+    }
+
+    #[test]
+    fn length_split_anywhere() {
+        for i in 0..=LENGTH.len() {
+            let (status, body, trailers) = parse(&[&LENGTH[..i], &LENGTH[i..]]).unwrap();
+            assert_eq!(status, [200], "split at {i}");
+            assert_eq!(String::from_utf8(body).unwrap(), "Hello wörld", "split at {i}");
+            assert!(trailers.is_empty());
+        }
+    }
+
+    #[test]
+    fn interim_responses() {
+        let data = [b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a>\r\n\r\n".as_slice(), LENGTH].concat();
+        for i in 0..=data.len() {
+            let (status, body, _) = parse(&[&data[..i], &data[i..]]).unwrap();
+            assert_eq!(status, [200], "split at {i}");
+            assert_eq!(body, "Hello wörld".as_bytes());
+        }
+    }
+
+    #[test]
+    fn no_content() {
+        let mut parser = ResponseParser::new();
+        let parts = parser.feed(b"HTTP/1.1 204 No Content\r\nContent-Length: 12\r\n\r\n").unwrap();
+        assert!(parser.is_done());
+        assert!(matches!(&parts[..], [Part::Head(head), Part::End(_)] if head.status == 204));
+    }
+
+    #[test]
+    fn truncated_body() {
+        assert!(parse(&[&LENGTH[..LENGTH.len() - 1]]).is_err());
+        assert!(parse(&[&CHUNKED[..CHUNKED.len() - 2]]).is_err());
+        assert!(parse(&[b"HTTP/1.1 200 OK\r\nContent-Le"]).is_err());
+        // NOTE: without a length the body ends with the connection
+        let (_, body, _) = parse(&[b"HTTP/1.1 200 OK\r\n\r\nHello"]).unwrap();
+        assert_eq!(body, b"Hello");
+    }
+
+    /// Hands out `data` a few bytes per read, cancelling the request at read `cancel_at`.
+    struct Reads<'a> {
+        data: &'a [u8],
+        cancel_at: Option<(usize, CancelHandle)>,
+    }
+    impl Read for Reads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some((n, cancel)) = &mut self.cancel_at {
+                if *n == 0 { // NOTE: like a socket shut down under the read
+                    cancel.cancel();
+                    return Ok(0);
+                }
+                *n -= 1;
+            }
+            let n = buf.len().min(self.data.len()).min(5);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    /// Streams the response `data`, each body line becoming a delta but for `finish`
+    /// and `error`, and returns the result and the events sent.
+    fn stream(data: &[u8], cancel_at: Option<usize>) -> (Result<(), GptError>, Vec<StreamEvent>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let sink = Sink::new(1, tx);
+        let cancel = CancelHandle::new();
+        let mut reads = Reads { data, cancel_at: cancel_at.map(|x| (x, cancel.clone())) };
+        let mut lines = LineBuffer::default();
+        let result = stream_response(&mut reads, &sink, &cancel, |data| {
+            lines.push(data).into_iter().map(|line| match line.as_str() {
+                "finish" => StreamEvent::Finished { reason: FinishReason::Length },
+                "error" => GptError::Provider("overloaded".to_string()).into(),
+                _ => StreamEvent::Delta { text: line },
+            }).collect()
+        });
+        let events = rx.try_iter().map(|(_, x)| x).collect();
+        std::mem::forget(sink); // NOTE: it would report itself unfinished after an error
+        (result, events)
+    }
+
+    fn delta(text: &str) -> StreamEvent {
+        StreamEvent::Delta { text: text.to_string() }
+    }
+
+    const START: StreamEvent = StreamEvent::Start { role: Role::AI };
+
+    #[test]
+    fn stream_finished_last() {
+        let data = b"HTTP/1.1 200 OK\r\n\r\nHel\nfinish\nlo\n";
+        let (result, events) = stream(data, None);
+        assert_eq!(result, Ok(()));
+        assert_eq!(events, [START, delta("Hel"), delta("lo"), StreamEvent::Finished { reason: FinishReason::Length }]);
+    }
+
+    #[test]
+    fn stream_status_error() {
+        let data = b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 7\r\nContent-Length: 35\r\n\r\n{\"error\": {\"message\": \"slow down\"}}";
+        let (result, events) = stream(data, None);
+        assert_eq!(result, Err(GptError::RateLimit { message: "slow down".to_string(), retry_after: Some(Duration::from_secs(7)) }));
+        assert!(events.is_empty(), "{events:?}");
+        let data = b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 17\r\n\r\n{\"error\": \"oops\"}";
+        let (result, _) = stream(data, None);
+        assert_eq!(result, Err(GptError::Status { code: 500, message: "oops".to_string(), retry_after: None }));
+    }
+
+    #[test]
+    fn stream_error_event() {
+        let (result, events) = stream(b"HTTP/1.1 200 OK\r\n\r\nHel\nerror\n", None);
+        assert_eq!(result, Ok(()));
+        assert_eq!(events, [
+            START,
+            delta("Hel"),
+            GptError::Provider("overloaded".to_string()).into(),
+            StreamEvent::Finished { reason: FinishReason::Error },
+        ]);
+    }
+
+    #[test]
+    fn stream_cancelled() {
+        // NOTE: 6 reads of 5 bytes get to the end of "wor"
+        let (result, events) = stream(b"HTTP/1.1 200 OK\r\n\r\nHel\nlo\nwor\nld\n", Some(6));
+        assert_eq!(result, Ok(()));
+        assert_eq!(events, [START, delta("Hel"), delta("lo"), delta("wor"), StreamEvent::Finished { reason: FinishReason::Cancelled }]);
+    }
+
+    #[test]
+    fn durations() {
+        let cases = [
+            ("1s", Some(1.0)),
+            ("6m0s", Some(360.0)),
+            ("20ms", Some(0.02)),
+            ("1h2m3.5s", Some(3723.5)),
+            ("2.5", Some(2.5)),
+            (" 7 ", Some(7.0)),
+            ("1d", None),
+            ("s", None),
+            ("-1", None),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(parse_duration(data), expected.map(Duration::from_secs_f64), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn http_dates() {
+        let cases = [
+            ("Thu, 01 Jan 1970 00:00:00 GMT", Some(0)),
+            ("Wed, 21 Oct 2015 07:28:00 GMT", Some(1445412480)),
+            ("Tue, 29 Feb 2000 12:00:00 GMT", Some(951825600)),
+            ("Sat, 17 Oct 2026 19:56:05 GMT", Some(1792266965)),
+            ("Wed, 21 Oct 2015 07:28:00 UTC", None),
+            ("Wed, 21 Foo 2015 07:28:00 GMT", None),
+            ("Wednesday, 21-Oct-15 07:28:00 GMT", None),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(parse_http_date(data), expected, "{data:?}");
+        }
+    }
+
+    fn head(status: u16, headers: &[(&str, &str)]) -> Head {
+        let headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        Head { status, reason: String::new(), headers }
+    }
+
+    #[test]
+    fn retry_after_headers() {
+        let secs = |x| Some(Duration::from_secs(x));
+        let cases = [
+            (head(429, &[("retry-after-ms", "1500"), ("retry-after", "9")]), Some(Duration::from_millis(1500))),
+            (head(503, &[("retry-after", "9")]), secs(9)),
+            (head(503, &[("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT")]), secs(0)),
+            (head(503, &[("retry-after", "soon")]), None),
+            (head(429, &[]), None),
+            (head(429, &[
+                ("x-ratelimit-remaining-requests", "0"), ("x-ratelimit-reset-requests", "6m0s"),
+                ("x-ratelimit-remaining-tokens", "100"), ("x-ratelimit-reset-tokens", "59m"),
+            ]), secs(360)),
+            (head(429, &[
+                ("x-ratelimit-remaining-requests", "0"), ("x-ratelimit-reset-requests", "2s"),
+                ("x-ratelimit-remaining-tokens", "0"), ("x-ratelimit-reset-tokens", "1m"),
+            ]), secs(60)),
+            (head(429, &[("x-ratelimit-reset-requests", "2s")]), None),
+            // NOTE: openai sends them along with every reply
+            (head(200, &[("x-ratelimit-remaining-requests", "0"), ("x-ratelimit-reset-requests", "2s")]), None),
+            (head(500, &[("x-ratelimit-remaining-tokens", "0"), ("x-ratelimit-reset-tokens", "2s")]), None),
+        ];
+        for (head, expected) in cases {
+            assert_eq!(retry_after(&head), expected, "{head:?}");
+        }
+    }
+
+    #[test]
+    fn endpoints() {
+        let endpoint = |tls, host: &str, port, path: &str, query: Option<&str>| Endpoint {
+            tls, host: host.to_string(), port, path: path.to_string(), query: query.map(|x| x.to_string()),
+        };
+        let cases = [
+            ("localhost:11434", endpoint(false, "localhost", 11434, "", None)),
+            ("127.0.0.1/v1", endpoint(false, "127.0.0.1", 80, "/v1", None)),
+            ("https://api.openai.com/v1/", endpoint(true, "api.openai.com", 443, "/v1", None)),
+            ("http://[::1]:8080/v1", endpoint(false, "::1", 8080, "/v1", None)),
+            ("https://[::1]", endpoint(true, "::1", 443, "", None)),
+            (
+                "https://x.openai.azure.com/openai/deployments/gpt4?api-version=2024-06-01",
+                endpoint(true, "x.openai.azure.com", 443, "/openai/deployments/gpt4", Some("api-version=2024-06-01")),
+            ),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(Endpoint::parse(url), Ok(expected), "{url}");
+        }
+        for url in ["http://localhost:port", "http://localhost:99999", "ftp://example.com", "https:///v1", "http://[::1/v1"] {
+            assert!(Endpoint::parse(url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn endpoint_requests() {
+        let endpoint = Endpoint::parse("http://[::1]:8080/v1/").unwrap();
+        assert_eq!(endpoint.host_header(), "[::1]:8080");
+        assert_eq!(endpoint.target("/models"), "/v1/models");
+        assert_eq!(endpoint.to_string(), "http://[::1]:8080/v1");
+        assert_eq!(Endpoint::parse("https://api.openai.com:443").unwrap().host_header(), "api.openai.com");
+
+        let azure = Endpoint::parse("https://x.openai.azure.com/openai/deployments/gpt4").unwrap();
+        let azure = azure.with_query_default("api-version", "2024-06-01");
+        assert_eq!(azure.target("/chat/completions"), "/openai/deployments/gpt4/chat/completions?api-version=2024-06-01");
+        let set = Endpoint::parse("https://x.openai.azure.com/openai?api-version=2025-01-01").unwrap();
+        assert_eq!(set.with_query_default("api-version", "2024-06-01").query.as_deref(), Some("api-version=2025-01-01"));
+        let other = Endpoint::parse("https://x.openai.azure.com/openai?foo=1").unwrap();
+        assert_eq!(other.with_query_default("api-version", "2024-06-01").query.as_deref(), Some("foo=1&api-version=2024-06-01"));
+    }
+}
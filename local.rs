@@ -0,0 +1,187 @@
+use std::time::Duration;
+use serde_json::Value;
+use crate::backend::{ChatBackend, ChatRequest, CancelHandle, FinishReason, Sink, StreamEvent, Usage};
+use crate::error::GptError;
+use crate::{gpt, http};
+use crate::http::{Endpoint, LineBuffer};
+use crate::params::Params;
+use crate::sse;
+
+const DEFAULT_MODEL: &str = "llama3";
+// NOTE: local models can take a while to load before the first token shows up
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// Ollama `/api/chat`, streams newline delimited json.
+    Ollama,
+    /// llama.cpp server `/v1/chat/completions`, streams openai style server-sent events.
+    LlamaCpp,
+}
+
+/// A model server on the local network, usually spoken to over plain HTTP.
+pub struct Local {
+    flavor: Flavor,
+    endpoint: Endpoint,
+}
+
+impl Local {
+    /// Ollama at OLLAMA_HOST (default 127.0.0.1:11434), which like Ollama itself
+    /// takes port 11434 when it has neither scheme nor port, e.g. `0.0.0.0`.
+    pub fn ollama() -> Result<Self, String> {
+        let url = std::env::var("OLLAMA_HOST").unwrap_or("127.0.0.1".to_string());
+        Local::new(Flavor::Ollama, &url)
+    }
+    /// llama.cpp server at LLAMA_CPP_HOST (default 127.0.0.1:8080), port 8080 too
+    /// when it has neither scheme nor port.
+    pub fn llama_cpp() -> Result<Self, String> {
+        let url = std::env::var("LLAMA_CPP_HOST").unwrap_or("127.0.0.1".to_string());
+        Local::new(Flavor::LlamaCpp, &url)
+    }
+    fn new(flavor: Flavor, url: &str) -> Result<Self, String> {
+        let port = match flavor {
+            Flavor::Ollama => 11434,
+            Flavor::LlamaCpp => 8080,
+        };
+        let endpoint = Endpoint::parse_with_port(url, port)?;
+        Ok(Local { flavor, endpoint })
+    }
+    fn make_prompt(&self, req: &ChatRequest) -> String {
+        let mut body = serde_json::json!({
+            "model": req.model,
+            "messages": gpt::to_messages(&req.messages),
+            "stream": true
+        });
+        let route = match self.flavor {
+            Flavor::Ollama => {
+                body["options"] = ollama_options(&req.params);
+                "/api/chat"
+            }
+            Flavor::LlamaCpp => {
+                gpt::add_params(&mut body, &req.params.to_json());
+                body["stream_options"] = serde_json::json!({"include_usage": true});
+                "/v1/chat/completions"
+            }
+        };
+        self.endpoint.make_post(route, &[], &body.to_string())
+    }
+}
+
+/// Ollama takes the sampling parameters in `options`, where max_tokens is `num_predict`.
+fn ollama_options(params: &Params) -> Value {
+    let mut options = params.to_json();
+    if let Some(max_tokens) = options.as_object_mut().and_then(|x| x.remove("max_tokens")) {
+        options["num_predict"] = max_tokens;
+    }
+    options
+}
+
+/// Extracts the content of one line of Ollama's json stream, the last one has
+/// `done` set along with the token counts.
+fn on_parse_line(data: &str) -> Vec<StreamEvent> {
+    if data.trim().is_empty() {
+        return Vec::new();
+    }
+    let data = match serde_json::from_str::<Value>(data) {
+        Ok(data) => data,
+        Err(_err) => return vec![GptError::Parse(format!("Could not parse {data}")).into()],
+    };
+    if let Some(msg) = data["error"].as_str() {
+        return vec![GptError::Provider(msg.to_string()).into()];
+    }
+    let mut events = Vec::new();
+    if let Some(text) = data["message"]["content"].as_str() {
+        events.push(StreamEvent::Delta { text: text.to_string() });
+    }
+    if data["done"].as_bool() == Some(true) {
+        if let (Some(prompt), Some(completion)) = (data["prompt_eval_count"].as_u64(), data["eval_count"].as_u64()) {
+            events.push(StreamEvent::Usage(Usage { prompt_tokens: prompt, completion_tokens: completion }));
+        }
+        let reason = data["done_reason"].as_str().unwrap_or("stop");
+        events.push(StreamEvent::Finished { reason: FinishReason::from(reason) });
+    }
+    events
+}
+
+impl ChatBackend for Local {
+    fn default_model(&self) -> &str {
+        DEFAULT_MODEL
+    }
+    fn prompt(&self, req: &ChatRequest, sink: &Sink, cancel: &CancelHandle) -> Result<(), GptError> {
+        let req = self.make_prompt(req);
+        let mut stream = self.endpoint.connect(READ_TIMEOUT, cancel)?;
+        match self.flavor {
+            Flavor::Ollama => {
+                let mut lines = LineBuffer::default();
+                http::post_streaming(&mut stream, &req, sink, cancel, |data| {
+                    lines.push(data).iter().flat_map(|x| on_parse_line(x)).collect()
+                })
+            }
+            Flavor::LlamaCpp => {
+                let mut decoder = sse::Decoder::default();
+                http::post_streaming(&mut stream, &req, sink, cancel, |data| gpt::on_parse_body(&mut decoder, data))
+            }
+        }
+    }
+    fn list_models(&self) -> Result<Vec<String>, GptError> {
+        let mut stream = self.endpoint.connect(READ_TIMEOUT, &CancelHandle::new())?;
+        match self.flavor {
+            Flavor::Ollama => { // NOTE: {"models": [{"name": ..}, ..]}
+                let models = http::fetch_json(&mut stream, &self.endpoint.make_get("/api/tags", &[]))?;
+                Ok(models["models"].as_array().into_iter().flatten()
+                    .filter_map(|x| x["name"].as_str())
+                    .map(|x| x.to_string())
+                    .collect())
+            }
+            Flavor::LlamaCpp => {
+                let models = http::fetch_json(&mut stream, &self.endpoint.make_get("/v1/models", &[]))?;
+                Ok(gpt::model_ids(&models))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ollama_host() {
+        let port = |url| Local::new(Flavor::Ollama, url).unwrap().endpoint.port;
+        assert_eq!(port("0.0.0.0"), 11434);
+        assert_eq!(port("127.0.0.1:8000"), 8000);
+        assert_eq!(port("[::1]"), 11434);
+        assert_eq!(port("http://ollama.lan"), 80);
+        assert_eq!(port("https://ollama.lan"), 443);
+        assert_eq!(Local::new(Flavor::LlamaCpp, "localhost").unwrap().endpoint.port, 8080);
+    }
+
+    #[test]
+    fn parse_lines() {
+        let mut lines = LineBuffer::default();
+        let data = "{\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n\
+            {\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"lö\"},\"done\":false}\n\
+            \n\
+            {\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done_reason\":\"length\",\"done\":true,\"prompt_eval_count\":26,\"eval_count\":2}\n";
+        let events: Vec<StreamEvent> = data.as_bytes().chunks(5)
+            .flat_map(|x| lines.push(x))
+            .flat_map(|x| on_parse_line(&x))
+            .collect();
+        assert_eq!(events, [
+            StreamEvent::Delta { text: "Hel".to_string() },
+            StreamEvent::Delta { text: "lö".to_string() },
+            StreamEvent::Delta { text: String::new() },
+            StreamEvent::Usage(Usage { prompt_tokens: 26, completion_tokens: 2 }),
+            StreamEvent::Finished { reason: FinishReason::Length },
+        ]);
+        let events = on_parse_line("{\"done\":true}");
+        assert_eq!(events, [StreamEvent::Finished { reason: FinishReason::Stop }]);
+    }
+
+    #[test]
+    fn parse_error_line() {
+        let events = on_parse_line("{\"error\":\"model 'llama9' not found\"}");
+        assert!(matches!(&events[..], [StreamEvent::Error { message, .. }] if message.contains("llama9")), "{events:?}");
+        assert!(matches!(&on_parse_line("{\"message\":")[..], [StreamEvent::Error { .. }]));
+    }
+}
@@ -1,29 +1,72 @@
+mod anthropic;
+mod backend;
+mod command;
+mod config;
+mod context;
+mod conversation;
+mod error;
 mod gpt;
+mod http;
+mod local;
+mod params;
 mod renderer;
+mod sidebar;
+mod sse;
+mod store;
+mod tokenizer;
 use crossterm::{QueueableCommand, ExecutableCommand, cursor};
-use renderer::{Buffer, render_diff, Position, Region, DEFAULT_BG, DEFAULT_FG};
-use std::fs::File;
+use renderer::{Buffer, render_diff, Position, Region, View, DEFAULT_BG, DEFAULT_FG};
 use std::io::{Write};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::mpsc::{self};
+use std::sync::Arc;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::{io::{self}, thread};
 use crossterm::{
     terminal, style, event::{self, KeyCode, KeyModifiers}
 };
 use cli_clipboard::{ClipboardContext, ClipboardProvider};
 use serde::de::Visitor;
+use backend::{ChatBackend, ChatRequest, CancelHandle, FinishReason, RequestId, RetryPolicy, Sink, StreamEvent, Usage};
+use command::{Command, COMMANDS};
+use config::{Config, Persona};
+use conversation::{Conversation, Message};
+use error::GptError;
+use params::Params;
+use sidebar::Sidebar;
+use store::{Entry, Store};
+use tokenizer::Tokenizer;
 
 const AI_COLOR: style::Color = style::Color::Blue;
 const INPUT_COLOR: style::Color = DEFAULT_FG;
-const SYSTEM_COLOR: style::Color = style::Color::Red;
+const SYSTEM_COLOR: style::Color = style::Color::Magenta;
+const NOTICE_COLOR: style::Color = style::Color::Red;
 const USER_COLOR: style::Color = style::Color::Green;
+const MENU_COLOR: style::Color = style::Color::DarkGrey;
+const TRIMMED_COLOR: style::Color = style::Color::DarkGrey;
+const SELECTED_BG: style::Color = style::Color::DarkGrey;
 const SCROLL_SPEED: usize = 3; // lines
-const CONV_FILE: &str = "conversation.json";
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(2);
+const MENU_HEIGHT: usize = 8; // lines
+const SIDEBAR_WIDTH: usize = 36; // columns
+/// Where older versions kept the one conversation, imported into the store.
+const LEGACY_CONV_FILE: &str = "conversation.json";
 const START_PREFIX: &str = "■  ";
+const TRUNCATED_MARK: &str = "[truncated]";
+const TITLE_PROMPT: &str = "Reply with a short title, at most 6 words, for the conversation below. \
+                            Reply with the title only, without quotes.";
+/// Characters of the first message and reply the title is made from.
+const TITLE_EXCERPT: usize = 1000;
+const TITLE_LENGTH: usize = 60;
+/// Room kept for the reply when trimming the context, unless max_tokens is set.
+const MAX_REPLY_RESERVE: usize = 4096;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Role {
-    User, System, AI
+    User, System, AI,
+    /// Shown to the user but never sent: errors, command output...
+    Notice,
 }
 struct RoleVisitor;
 impl<'a> Visitor<'a> for RoleVisitor {
@@ -59,78 +102,719 @@ impl Role {
             "user" => Some(Role::User),
             "system" => Some(Role::System),
             "assistant" => Some(Role::AI),
+            "notice" => Some(Role::Notice),
             _ => None
         }
     }
-    fn value(&self) -> &str {
+    pub fn value(&self) -> &str {
         match self {
             Role::User => "user",
             Role::System => "system",
             Role::AI => "assistant",
+            Role::Notice => "notice",
         }
     }
 }
 
+/// Models offered by the backend, fetched the first time the user types `/model`.
+enum Models {
+    Unknown,
+    Fetching,
+    Ready(Vec<String>),
+    Failed(String),
+}
+
+/// A background request for the title of conversation `conv_id`.
+struct TitleRequest {
+    id: RequestId,
+    conv_id: String,
+    text: String,
+}
+
 struct State {
-    conv: Vec<(Role, String)>,
+    config: Config,
+    /// Model of the backend, for conversations that don't name one.
+    default_model: String,
+    store: Store,
+    /// Id of the open conversation in the store.
+    conv_id: String,
+    /// As listed by the store, refreshed when conversations are opened, created,
+    /// renamed or deleted.
+    conversations: Vec<Entry>,
+    conv: Conversation,
     input: String,
     view_start: usize,
+    cancel: CancelHandle,
+    /// The request whose reply is streaming in, events of any other are stale.
+    request: Option<RequestId>,
+    next_request: RequestId,
+    /// Why the request in flight failed and when it is sent again, shown on the
+    /// separator line with a countdown.
+    retry_status: Option<(String, Instant)>,
+    models: Models,
+    /// Selected entry of the completion menu.
+    menu_index: usize,
+    /// Parameters set with `/once`, used by the next message only.
+    once: Params,
+    /// Messages left out of the last request to fit the context window.
+    dropped: Range<usize>,
+    /// The conversation browser, when open.
+    sidebar: Option<Sidebar>,
+    /// The first exchange of an untitled conversation just finished.
+    title_wanted: bool,
+    /// Request for the title of a conversation, streaming in.
+    title: Option<TitleRequest>,
+    last_save: Instant,
+    /// The last save failed, and the user was told.
+    save_failed: bool,
+    /// Id of the user message selected for editing with `Up` / `Down`.
+    selected: Option<u64>,
+    /// Id of the user message being edited in the input, replaced along with
+    /// everything after it when the edit is sent.
+    editing: Option<u64>,
+    /// Id of the reply being regenerated, it is left out of the request and moved
+    /// to a branch once the new reply starts streaming.
+    regenerating: Option<u64>,
+    /// Id of the message the reply in flight streams into, notices may come after it.
+    reply: Option<u64>,
+    /// Request prepared by [`State::send`], spawned by the main loop.
+    outgoing: Option<(RequestId, ChatRequest)>,
 }
 impl State {
-    fn new() -> Self {
-        State{ conv: Vec::new(), input: String::new(), view_start: 0 }
+    fn new(config: Config, store: Store, default_model: String) -> Self {
+        State{ config, default_model, store, conv_id: String::new(), conversations: Vec::new(),
+               conv: Conversation::default(), input: String::new(), view_start: 0, cancel: CancelHandle::new(),
+               request: None, next_request: 0, retry_status: None, models: Models::Unknown, menu_index: 0,
+               once: Params::default(), dropped: 0..0, sidebar: None, title_wanted: false, title: None,
+               last_save: Instant::now(), save_failed: false, selected: None, editing: None,
+               regenerating: None, reply: None, outgoing: None }
+    }
+    /// Saves the open conversation, a failure is reported once until a save succeeds again.
+    fn save(&mut self) {
+        self.last_save = Instant::now();
+        match self.conv.save(&self.store.path(&self.conv_id)) {
+            Ok(()) => self.save_failed = false,
+            Err(err) if !self.save_failed => {
+                self.save_failed = true;
+                self.append_conv(Role::Notice, err);
+            }
+            Err(_err) => {}
+        }
+    }
+    /// Makes `conv` the open conversation, stopping the request in flight.
+    /// The one open so far is not saved, callers do that if it still exists.
+    fn switch(&mut self, id: String, mut conv: Conversation) {
+        self.cancel_request();
+        // NOTE: the backend's default is not saved, it is "" when the backend could not be set up
+        conv.model = conv.model.filter(|x| !x.is_empty()).or(self.config.model.clone());
+        self.conv = conv;
+        self.conv_id = id;
+        self.dropped = 0..0;
+        self.view_start = 0;
+        self.selected = None;
+        self.editing = None;
+        self.store.set_last(&self.conv_id);
+        self.conversations = self.store.list();
+    }
+    /// Starts a conversation with the configured persona.
+    fn new_conversation(&mut self, title: Option<String>) {
+        self.switch(self.store.new_id(), Conversation { title, ..Default::default() });
+        if let Some(persona) = self.config.persona.as_ref().and_then(|x| self.config.persona(x)).cloned() {
+            self.apply_persona(&persona);
+        }
+        self.save();
+        self.conversations = self.store.list();
+    }
+    /// Saves the open conversation and switches to the one with id `id`,
+    /// salvaging what it can when its file is corrupt.
+    fn open(&mut self, id: String) {
+        match Conversation::recover(&self.store.path(&id)) {
+            Ok((conv, note)) => {
+                self.save();
+                self.switch(id, conv);
+                if let Some(note) = note {
+                    self.append_conv(Role::Notice, note);
+                    self.save();
+                }
+            }
+            Err(err) => self.append_conv(Role::Notice, err),
+        }
+    }
+    /// Deletes the conversation with id `id`. When it is the open one, the most
+    /// recent conversation left is opened instead, or a new one.
+    fn delete(&mut self, id: String) {
+        if id == self.conv_id {
+            self.cancel_request();
+        }
+        if let Err(err) = self.store.delete(&id) {
+            return self.append_conv(Role::Notice, err);
+        }
+        self.conversations = self.store.list();
+        if id == self.conv_id {
+            let next = self.conversations.first()
+                .and_then(|x| Some((x.id.clone(), Conversation::load(&self.store.path(&x.id)).ok()?)));
+            match next {
+                Some((id, conv)) => self.switch(id, conv),
+                None => self.new_conversation(None),
+            }
+        }
+        self.append_conv(Role::Notice, "Conversation deleted".to_string());
+    }
+    /// Opens the conversation browser on the open conversation.
+    fn toggle_sidebar(&mut self) {
+        if self.sidebar.take().is_some() {
+            return;
+        }
+        self.conversations = self.store.list();
+        let mut sidebar = Sidebar::default();
+        sidebar.select(self.conversations.iter().position(|x| x.id == self.conv_id).unwrap_or_default(), &self.conversations);
+        self.sidebar = Some(sidebar);
+    }
+    /// Handles `key` while the sidebar is open, false for the keys it leaves alone.
+    fn sidebar_key(&mut self, key: event::KeyEvent) -> bool {
+        let Some(sidebar) = &mut self.sidebar else { return false };
+        match key.code {
+            KeyCode::Esc => self.sidebar = None,
+            KeyCode::Char('b') if key.modifiers == KeyModifiers::CONTROL => self.sidebar = None,
+            KeyCode::Char(c) if key.modifiers == KeyModifiers::NONE || key.modifiers == KeyModifiers::SHIFT => {
+                sidebar.filter.push(c);
+                sidebar.select(0, &self.conversations);
+            }
+            KeyCode::Backspace => {
+                sidebar.filter.pop();
+                sidebar.select(0, &self.conversations);
+            }
+            KeyCode::Up => sidebar.select(sidebar.index.saturating_sub(1), &self.conversations),
+            KeyCode::Down => sidebar.select(sidebar.index + 1, &self.conversations),
+            KeyCode::Enter => self.open_selected(),
+            KeyCode::Delete => {
+                let Some(entry) = sidebar.selected(&self.conversations) else { return true };
+                if !sidebar.confirm_delete {
+                    sidebar.confirm_delete = true;
+                    return true;
+                }
+                let (id, index) = (entry.id.clone(), sidebar.index);
+                self.delete(id);
+                if let Some(sidebar) = &mut self.sidebar {
+                    sidebar.select(index, &self.conversations);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+    /// Opens the conversation shown on `row` of the sidebar.
+    fn sidebar_click(&mut self, row: usize) {
+        let Some(sidebar) = &mut self.sidebar else { return };
+        let Some(index) = sidebar.entry_at(row).filter(|x| *x < sidebar.matches(&self.conversations).len()) else { return };
+        sidebar.select(index, &self.conversations);
+        self.open_selected();
+    }
+    /// Opens the conversation selected in the sidebar and closes it.
+    fn open_selected(&mut self) {
+        let selected = self.sidebar.as_ref().and_then(|x| x.selected(&self.conversations));
+        if let Some(id) = selected.map(|x| x.id.clone()) {
+            self.sidebar = None;
+            self.open(id);
+        }
+    }
+    /// Handles `key` while a message is selected to edit it or switch its branch,
+    /// `Up` on an empty input starts selecting. False for the keys it leaves alone.
+    fn select_key(&mut self, key: event::KeyEvent) -> bool {
+        let ids: Vec<u64> = self.conv.messages.iter()
+            .filter(|x| x.role == Role::User || x.role == Role::AI)
+            .map(|x| x.id)
+            .collect();
+        let Some(index) = self.selected.and_then(|id| ids.iter().position(|x| *x == id)) else {
+            self.selected = None;
+            match key.code {
+                KeyCode::Up if self.input.is_empty() && key.modifiers == KeyModifiers::NONE => {
+                    self.selected = ids.last().copied();
+                    return self.selected.is_some();
+                }
+                KeyCode::Esc if self.editing.is_some() => {
+                    self.editing = None;
+                    self.input.clear();
+                    return true;
+                }
+                _ => return false,
+            }
+        };
+        match key.code {
+            KeyCode::Up => self.selected = Some(ids[index.saturating_sub(1)]),
+            KeyCode::Down => self.selected = ids.get(index + 1).copied(),
+            KeyCode::Left | KeyCode::Right => {
+                let Some(i) = self.conv.messages.iter().position(|x| x.id == ids[index]) else { return true };
+                self.switch_branch(i, if key.code == KeyCode::Left { -1 } else { 1 });
+                self.selected = self.conv.messages.get(i).map(|x| x.id);
+            }
+            KeyCode::Enter => {
+                let id = ids[index];
+                if let Some(msg) = self.conv.messages.iter().find(|x| x.id == id && x.role == Role::User) {
+                    self.input = msg.content.clone();
+                    self.editing = Some(id);
+                    self.selected = None;
+                }
+            }
+            KeyCode::Esc => self.selected = None,
+            _ => {
+                self.selected = None;
+                return false;
+            }
+        }
+        true
+    }
+    /// Forks the conversation at the message with id `id`, to send an edit of it instead.
+    fn fork_at(&mut self, id: u64) {
+        if let Some(index) = self.conv.messages.iter().position(|x| x.id == id) {
+            self.conv.fork(index);
+            self.dropped = 0..0;
+        }
+    }
+    /// Switches the active path at `index` to another branch, see [`Conversation::switch_branch`].
+    fn switch_branch(&mut self, index: usize, step: isize) {
+        if self.request.is_some() { // NOTE: the reply streaming in would end up on the wrong branch
+            return;
+        }
+        self.conv.switch_branch(index, step);
+        self.dropped = 0..0;
+        self.save();
+    }
+    /// How to go on when selecting or editing a message, shown on the separator line.
+    fn edit_status(&self) -> Option<String> {
+        if self.selected.is_some() {
+            Some("Up / Down: pick a message · Left / Right: switch branch · Enter: edit it · Esc: back".to_string())
+        } else if self.editing.is_some() {
+            Some("editing, Enter resends it on a new branch · Esc: cancel".to_string())
+        } else {
+            None
+        }
+    }
+    /// Error message when another conversation already has `title`.
+    fn check_title(&self, title: &str) -> Result<(), String> {
+        match self.conversations.iter().find(|x| x.name() == title && x.id != self.conv_id) {
+            Some(_) => Err(format!("There already is a conversation called {title}")),
+            None => Ok(()),
+        }
     }
     fn append_conv(&mut self, role: Role, msg: String) {
-        self.conv.push((role, msg));
+        self.conv.push(role, msg);
+    }
+    /// Wraps up the request in flight, the conversation is saved whatever the outcome.
+    fn finish_request(&mut self, reason: FinishReason) {
+        if let Some(msg) = self.reply_mut() {
+            msg.meta.finish_reason = Some(reason.clone());
+        }
+        self.reply = None;
+        match reason {
+            FinishReason::Length => {
+                self.append_conv(Role::Notice, "The reply was cut off at the token limit".to_string());
+            }
+            FinishReason::Stop => {
+                let replies = self.conv.messages.iter().filter(|x| x.role == Role::AI).count();
+                self.title_wanted = self.conv.title.is_none() && replies == 1;
+            }
+            _ => {}
+        }
+        self.request = None;
+        self.regenerating = None;
+        self.retry_status = None;
+        self.save();
+    }
+    /// The message the reply in flight streams into.
+    fn reply_mut(&mut self) -> Option<&mut Message> {
+        let id = self.reply?;
+        self.conv.messages.iter_mut().rev().find(|x| x.id == id)
+    }
+    /// Asks for a title of the open conversation once its first exchange is
+    /// done, the reply is tracked in `title`.
+    fn title_request(&mut self) -> Option<(RequestId, ChatRequest)> {
+        if !std::mem::take(&mut self.title_wanted) {
+            return None;
+        }
+        let excerpt = |role: Role| {
+            let msg = self.conv.messages.iter().find(|x| x.role == role).map_or("", |x| x.content.as_str());
+            msg.chars().take(TITLE_EXCERPT).collect::<String>()
+        };
+        let prompt = format!("User: {}\n\nAssistant: {}", excerpt(Role::User), excerpt(Role::AI));
+        let req = ChatRequest {
+            model: self.config.title_model.clone().unwrap_or(self.model().to_string()),
+            messages: vec![(Role::System, TITLE_PROMPT.to_string()), (Role::User, prompt)],
+            params: Params { max_tokens: Some(20), ..Default::default() },
+        };
+        self.next_request += 1;
+        self.title = Some(TitleRequest { id: self.next_request, conv_id: self.conv_id.clone(), text: String::new() });
+        Some((self.next_request, req))
+    }
+    /// Stores the title once its request is done, failures leave the conversation untitled.
+    fn finish_title(&mut self, reason: FinishReason) {
+        let Some(request) = self.title.take() else { return };
+        let title = request.text.lines().find(|x| !x.trim().is_empty()).unwrap_or_default();
+        let title = title.trim().trim_start_matches("Title:").trim().trim_matches(|x| "\"'*#.".contains(x)).trim();
+        if !matches!(reason, FinishReason::Stop | FinishReason::Length) || title.is_empty() {
+            return;
+        }
+        let mut title: String = title.chars().take(TITLE_LENGTH).collect();
+        let base = title.clone();
+        let mut n = 1;
+        while self.conversations.iter().any(|x| x.name() == title && x.id != request.conv_id) {
+            n += 1;
+            title = format!("{base} ({n})");
+        }
+        if request.conv_id == self.conv_id {
+            if self.conv.title.is_none() {
+                self.conv.title = Some(title);
+                self.save();
+            }
+        } else if let Ok(mut conv) = Conversation::load(&self.store.path(&request.conv_id)) {
+            if conv.title.is_none() {
+                conv.title = Some(title);
+                if let Err(err) = conv.save(&self.store.path(&request.conv_id)) {
+                    self.append_conv(Role::Notice, err);
+                }
+            }
+        }
+        self.conversations = self.store.list();
+    }
+    /// Model of the conversation, the backend's default when it has none.
+    fn model(&self) -> &str {
+        self.conv.model.as_deref().filter(|x| !x.is_empty()).unwrap_or(&self.default_model)
+    }
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::Model(None) => {
+                let mut msg = format!("Model: {}", self.model());
+                if let Models::Ready(models) = &self.models {
+                    msg.push_str(&format!("\nAvailable: {}", models.join(", ")));
+                }
+                self.append_conv(Role::Notice, msg);
+            }
+            Command::Model(Some(model)) => {
+                self.append_conv(Role::Notice, format!("Switched to model {model}"));
+                self.conv.model = Some(model);
+                self.save();
+            }
+            Command::Set { name: None, .. } => {
+                let mut msg = format!("Parameters: {}", self.config.params.merge(&self.conv.params));
+                if !self.once.is_empty() {
+                    msg.push_str(&format!("\nNext message only: {}", self.once));
+                }
+                self.append_conv(Role::Notice, msg);
+            }
+            Command::Set { name: Some(name), value } => {
+                match self.conv.params.set(&name, value.as_deref()) {
+                    Ok(()) => {
+                        let msg = match value {
+                            Some(value) => format!("Set {name} to {value}"),
+                            None => format!("Unset {name}"),
+                        };
+                        self.append_conv(Role::Notice, msg);
+                        self.save();
+                    }
+                    Err(err) => self.append_conv(Role::Notice, err),
+                }
+            }
+            Command::Persona(None) => {
+                let names: Vec<_> = self.config.personas.iter().map(|x| x.name.as_str()).collect();
+                let msg = format!("Persona: {}\nAvailable: {}", self.conv.persona.as_deref().unwrap_or("none"),
+                                  if names.is_empty() { "none, define them in the config file".to_string() } else { names.join(", ") });
+                self.append_conv(Role::Notice, msg);
+            }
+            Command::Persona(Some(name)) => {
+                match self.config.persona(&name).cloned() {
+                    Some(persona) => {
+                        self.apply_persona(&persona);
+                        self.append_conv(Role::Notice, format!("Switched to persona {name}"));
+                        self.save();
+                    }
+                    None => self.append_conv(Role::Notice, format!("Unknown persona {name}")),
+                }
+            }
+            Command::System(prompt) => {
+                let msg = if prompt.is_some() { "System prompt set" } else { "System prompt removed" };
+                self.set_system(prompt);
+                self.conv.persona = None;
+                self.append_conv(Role::Notice, msg.to_string());
+                self.save();
+            }
+            Command::New(title) => {
+                if let Err(err) = title.as_deref().map_or(Ok(()), |x| self.check_title(x)) {
+                    return self.append_conv(Role::Notice, err);
+                }
+                self.save();
+                self.new_conversation(title);
+            }
+            Command::Open(name) => match self.store.find(&name) {
+                Some(entry) => self.open(entry.id),
+                None => self.append_conv(Role::Notice, format!("No conversation called {name}")),
+            },
+            Command::Rename(title) => {
+                if let Err(err) = self.check_title(&title) {
+                    return self.append_conv(Role::Notice, err);
+                }
+                self.append_conv(Role::Notice, format!("Renamed to {title}"));
+                self.conv.title = Some(title);
+                self.save();
+                self.conversations = self.store.list();
+            }
+            Command::Delete(None) => self.delete(self.conv_id.clone()),
+            Command::Delete(Some(name)) => match self.store.find(&name) {
+                Some(entry) => self.delete(entry.id),
+                None => self.append_conv(Role::Notice, format!("No conversation called {name}")),
+            },
+            Command::List => {
+                let lines: Vec<_> = self.conversations.iter()
+                    .map(|x| format!("{} {}", if x.id == self.conv_id { "*" } else { "-" }, x.name()))
+                    .collect();
+                self.append_conv(Role::Notice, format!("Conversations:\n{}", lines.join("\n")));
+            }
+            Command::Once { name, value } => {
+                match self.once.set(&name, Some(&value)) {
+                    Ok(()) => self.append_conv(Role::Notice, format!("Set {name} to {value} for the next message")),
+                    Err(err) => self.append_conv(Role::Notice, err),
+                }
+            }
+            Command::Regenerate => self.regenerate(),
+        }
+    }
+    /// Replaces the system prompt, which stays at the top of the conversation.
+    fn set_system(&mut self, prompt: Option<String>) {
+        let start = self.conv.messages.iter().position(|x| x.role == Role::User || x.role == Role::AI);
+        let parent = start.and_then(|x| self.conv.parent(x));
+        let mut i = 0;
+        self.conv.messages.retain(|x| {
+            i += 1;
+            x.role != Role::System || start.is_some_and(|start| i > start)
+        });
+        if let Some(prompt) = prompt {
+            self.conv.messages.insert(0, Message::new(self.conv.next_id(), Role::System, prompt));
+        }
+        // NOTE: branches forked at the first prompt now follow the new system prompt
+        if let Some(start) = self.conv.messages.iter().position(|x| x.role == Role::User || x.role == Role::AI) {
+            self.conv.reparent(parent, self.conv.parent(start));
+        }
+        self.dropped = 0..0;
+    }
+    /// Takes over the system prompt, model and parameters of `persona`.
+    fn apply_persona(&mut self, persona: &Persona) {
+        self.set_system(persona.system.clone());
+        if let Some(model) = &persona.model {
+            self.conv.model = Some(model.clone());
+        }
+        self.conv.params = persona.params.clone();
+        self.conv.persona = Some(persona.name.clone());
+    }
+    /// Token totals of the conversation and their estimated cost, marked when
+    /// some replies are of models missing from the price table.
+    fn usage_status(&self) -> Option<String> {
+        let total = self.conv.usage();
+        if total == Usage::default() {
+            return None;
+        }
+        let mut cost = 0.0;
+        let mut unpriced = false;
+        for msg in self.conv.all_messages() {
+            let Some(usage) = msg.meta.usage else { continue };
+            match config::price(&self.config.prices, msg.meta.model.as_deref().unwrap_or_default()) {
+                Some(price) => cost += price.cost(usage),
+                None => unpriced = true,
+            }
+        }
+        let last = self.conv.messages.iter().rev().find_map(|x| x.meta.usage).unwrap_or_default();
+        let mut status = format!("tokens: last reply {} in / {} out, total {} in / {} out",
+                                 last.prompt_tokens, last.completion_tokens, total.prompt_tokens, total.completion_tokens);
+        match (cost > 0.0, unpriced) {
+            (true, false) => status.push_str(&format!(", ~${cost:.4}")),
+            (true, true) => status.push_str(&format!(", ~${cost:.4} + unpriced")),
+            (false, _) => {}
+        }
+        Some(status)
+    }
+    /// Messages to send to `model`, the oldest turns dropped when they don't fit
+    /// its context window along with `max_tokens` for the reply.
+    fn history(&mut self, params: &Params) -> Vec<(Role, String)> {
+        let model = self.model();
+        let limit = context::limit(&self.config.context_limits, model);
+        let reserve = params.max_tokens.map_or((limit / 4).min(MAX_REPLY_RESERVE), |x| x as usize);
+        let (sent, messages): (Vec<usize>, Vec<_>) = self.conv.history().into_iter()
+            .filter(|(i, _)| Some(self.conv.messages[*i].id) != self.regenerating)
+            .unzip();
+        let fit = context::fit(&messages, &Tokenizer::for_model(model), limit, reserve);
+        // NOTE: in the view, the notices in between are greyed out along with the dropped messages
+        self.dropped = match fit.dropped.is_empty() {
+            true => 0..0,
+            false => sent[fit.dropped.start]..sent[fit.dropped.end],
+        };
+        fit.apply(&messages)
+    }
+    /// Parameters for the next request: the config's, overridden by the
+    /// conversation's, overridden by the ones set with `/once`.
+    fn params(&self) -> Params {
+        self.config.params.merge(&self.conv.params).merge(&self.once)
+    }
+    /// Entries of the completion menu for what is typed so far: command names,
+    /// model names after `/model `, parameter names after `/set ` and `/once `.
+    fn completions(&self) -> Vec<String> {
+        for command in ["/set ", "/once "] {
+            if let Some(prefix) = self.input.strip_prefix(command).filter(|x| !x.contains(' ')) {
+                return params::NAMES.iter()
+                    .filter(|x| x.starts_with(prefix) && **x != prefix)
+                    .map(|x| format!("{command}{x} "))
+                    .collect();
+            }
+        }
+        if let Some(prefix) = self.input.strip_prefix("/persona ") {
+            return self.config.personas.iter()
+                .filter(|x| x.name.starts_with(prefix) && x.name != prefix)
+                .map(|x| format!("/persona {}", x.name))
+                .collect();
+        }
+        for command in ["/open ", "/delete "] {
+            if let Some(prefix) = self.input.strip_prefix(command) {
+                return self.conversations.iter()
+                    .filter(|x| x.name().contains(prefix) && x.name() != prefix && x.id != self.conv_id)
+                    .map(|x| format!("{command}{}", x.name()))
+                    .collect();
+            }
+        }
+        if let Some(prefix) = self.input.strip_prefix("/model ") {
+            return match &self.models {
+                Models::Ready(models) => models.iter()
+                    .filter(|x| x.contains(prefix.trim()))
+                    .map(|x| format!("/model {x}"))
+                    .collect(),
+                _ => Vec::new(),
+            };
+        }
+        if self.input.starts_with('/') && !self.input.contains(' ') {
+            return COMMANDS.iter()
+                .filter(|x| x.starts_with(&self.input) && **x != self.input)
+                .map(|x| format!("{x} "))
+                .collect();
+        }
+        Vec::new()
+    }
+    /// Prepares the request for a reply to the conversation, see `outgoing`.
+    fn send(&mut self) {
+        self.cancel = CancelHandle::new();
+        self.next_request += 1;
+        self.request = Some(self.next_request);
+        let params = self.params();
+        let req = ChatRequest { model: self.model().to_string(), messages: self.history(&params), params };
+        self.once = Params::default();
+        self.outgoing = Some((self.next_request, req));
+    }
+    /// Asks for another reply to the last prompt. The notices after it go, the
+    /// reply stays until the new one starts streaming.
+    fn regenerate(&mut self) {
+        self.cancel_request();
+        let Some(prompt) = self.conv.messages.iter().rposition(|x| x.role == Role::User) else {
+            return self.append_conv(Role::Notice, "There is no prompt to answer".to_string());
+        };
+        let reply = self.conv.messages[prompt..].iter().find(|x| x.role == Role::AI).map(|x| x.id);
+        let mut i = 0;
+        self.conv.messages.retain(|x| {
+            i += 1;
+            i <= prompt + 1 || Some(x.id) == reply
+        });
+        self.regenerating = reply;
+        self.send();
+    }
+    /// Shows the previous or next reply to the last prompt.
+    fn cycle_reply(&mut self, step: isize) {
+        if let Some(index) = self.conv.messages.iter().rposition(|x| x.role == Role::AI) {
+            self.switch_branch(index, step);
+        }
+    }
+    /// Stops the request in flight, its remaining events are ignored from now on.
+    fn cancel_request(&mut self) {
+        self.cancel.cancel();
+        if self.request.is_some() {
+            self.finish_request(FinishReason::Cancelled);
+        }
     }
 }
 
 fn split_by_length(mut str: &str, length: usize) -> Vec<&str> {
     let mut result = Vec::new();
-    while str.len() > length {
-        result.push(&str[..length]);
-        str = &str[length..];
+    // NOTE: counted in chars, slicing in the middle of one panics
+    while let Some((i, _)) = str.char_indices().nth(length.max(1)) {
+        result.push(&str[..i]);
+        str = &str[i..];
     }
     result.push(str);
-    return result;
+    result
+}
+
+/// Columns of the conversation browser on a screen `width` wide.
+fn sidebar_width(width: usize) -> usize {
+    SIDEBAR_WIDTH.min(width / 2)
 }
 
-fn render_conversation(state: &mut State, buffer: &mut Buffer,
-                       row: usize, height: usize, width: usize) {
-    let mut cur_row = row;
-    let mut current_role = &Role::AI;
-    let mut current_color = AI_COLOR;
-    let conv = state.conv.iter().rev();
-    let conv_iter = conv.flat_map(|(role, content)| {
+fn render_conversation(state: &mut State, view: &mut View) {
+    let (height, width) = (view.height(), view.width());
+    let mut cur_row = height.saturating_sub(1);
+    let dropped = state.dropped.clone();
+    let marker = format!("✂  {} earlier messages are not sent, they don't fit the context window", dropped.len());
+    let highlight = state.selected.or(state.editing);
+    let versions: Vec<String> = (0..state.conv.messages.len())
+        .map(|i| match state.conv.version(i) {
+            (_, 1) => String::new(),
+            (n, count) => format!("< {n}/{count} >"),
+        })
+        .collect();
+    let conv = state.conv.messages.iter().enumerate().rev();
+    let conv_iter = conv.flat_map(|(i, Message { id, role, content, meta, .. })| {
+        let bg = (Some(*id) == highlight).then_some(SELECTED_BG);
+        let color = match role {
+            _ if dropped.contains(&i) && bg.is_none() => TRIMMED_COLOR,
+            Role::AI => AI_COLOR,
+            Role::User => USER_COLOR,
+            Role::System => SYSTEM_COLOR,
+            Role::Notice => NOTICE_COLOR,
+        };
         let mut lines = content.rsplit("\n")
                .flat_map(|x|  {
-                   let mut result = split_by_length(&x, width as usize);
+                   let mut result = split_by_length(x, width);
                    result.reverse();
                    result
                })
-               .map(|x| (false, role, x)).collect::<Vec<_>>();
-        lines.last_mut().unwrap().0 = true;
+               .map(|x| (i, false, color, bg, x)).collect::<Vec<_>>();
+        lines.last_mut().unwrap().1 = true;
+        if meta.finish_reason == Some(FinishReason::Cancelled) {
+            lines.insert(0, (i, false, TRIMMED_COLOR, bg, TRUNCATED_MARK));
+        }
+        if !versions[i].is_empty() {
+            lines.insert(0, (i, false, TRIMMED_COLOR, bg, versions[i].as_str()));
+        }
+        if i == dropped.end && !dropped.is_empty() { // NOTE: above the first message that is sent
+            lines.push((i, false, TRIMMED_COLOR, None, marker.as_str()));
+        }
         lines
     });
+    // NOTE: scrolls the message selected for editing into view, lines are counted from the bottom
+    if let Some(selected) = state.selected.and_then(|id| state.conv.messages.iter().position(|x| x.id == id)) {
+        let mut rows = conv_iter.clone().enumerate().filter(|(_, x)| x.0 == selected && x.3.is_some()).map(|(row, _)| row);
+        if let Some(bottom) = rows.next() {
+            let top = rows.last().unwrap_or(bottom);
+            if bottom < state.view_start {
+                state.view_start = bottom;
+            } else if top >= state.view_start + height {
+                state.view_start = top + 1 - height;
+            }
+        }
+    }
     let count = conv_iter.clone().count() as i32;
     if count <= height as i32 {
         state.view_start = 0;
     } else if (count - state.view_start as i32) < (height as i32) {
         state.view_start = (count - height as i32) as usize;
     }
-    for (is_first, role, content) in conv_iter.skip(state.view_start) {
-        if current_role != role { // NOTE: switch role or first line
-            current_role = role;
-            current_color = match *current_role {
-                Role::AI => AI_COLOR,
-                Role::User => USER_COLOR,
-                Role::System => SYSTEM_COLOR,
-            }
-        }
+    for (_, is_first, color, bg, content) in conv_iter.skip(state.view_start) {
         if is_first {
-            buffer.put_line(cur_row, Some(current_color), None, &format!("{START_PREFIX}{content}"));
+            view.put_line(cur_row, Some(color), bg, &format!("{START_PREFIX}{content}"));
         } else {
-            buffer.put_line(cur_row, Some(current_color), None, content);
+            view.put_line(cur_row, Some(color), bg, content);
         };
         if cur_row == 0 { break; }
         cur_row -= 1;
@@ -138,27 +822,94 @@ fn render_conversation(state: &mut State, buffer: &mut Buffer,
 }
 
 
-fn save_conversation(file_path: &str, conversation: &Vec<(Role, String)>) {
-    let mut file = File::create(file_path).unwrap();
-    file.write_all(serde_json::ser::to_string(conversation).unwrap().as_bytes()).unwrap();
+/// The title of the conversation, its model and persona.
+fn render_header(state: &State, view: &mut View) {
+    let mut header = format!("—— {} · {}", state.conv.title.as_deref().unwrap_or("untitled"), state.model());
+    if let Some(persona) = &state.conv.persona {
+        header.push_str(&format!(" · {persona}"));
+    }
+    view.put_line(0, Some(INPUT_COLOR), Some(DEFAULT_BG), &format!("{header} {}", "—".repeat(view.width())));
 }
 
-fn load_conversation(file_path: &str) -> Result<Vec<(Role, String)>, String> {
-    if let Ok(data) = std::fs::read(file_path) {
-        serde_json::from_slice::<Vec<(Role, String)>>(data.as_slice())
-            .map_err(|_err| format!("Could not parse file {file_path}"))
-    } else {
-        Err(format!("Could not read file {file_path}"))
+/// Draws the completion menu bottom up from `row`, the selected entry highlighted.
+fn render_menu(state: &State, entries: &[String], view: &mut View, row: usize) {
+    let lines: Vec<(String, bool)> = match &state.models {
+        Models::Fetching if entries.is_empty() && state.input.starts_with("/model ") => {
+            vec![("fetching models...".to_string(), false)]
+        }
+        Models::Failed(err) if entries.is_empty() && state.input.starts_with("/model ") => {
+            vec![(format!("could not fetch models: {err}"), false)]
+        }
+        _ => entries.iter().enumerate()
+            .map(|(i, x)| (x.clone(), i == state.menu_index))
+            .collect(),
+    };
+    for (i, (line, selected)) in lines.iter().take(MENU_HEIGHT.min(row + 1)).enumerate() {
+        let bg = if *selected { INPUT_COLOR } else { MENU_COLOR };
+        let fg = if *selected { MENU_COLOR } else { INPUT_COLOR };
+        let line = format!(" {line}{}", " ".repeat(view.width()));
+        view.put_line(row - i, Some(fg), Some(bg), &line);
     }
 }
 
 fn main() -> io::Result<()> {
+    let (config, warnings) = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+    let (backend, backend_err): (Arc<dyn ChatBackend>, _) = match backend::from_env() {
+        Ok(backend) => (backend, None),
+        Err(err) => (Arc::new(backend::Unavailable(err.clone())), Some(err)),
+    };
+    let dir = config::data_dir().unwrap_or(PathBuf::from(".gpterm")).join("conversations");
+    let store = match Store::open(dir) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
     terminal::enable_raw_mode()?;
-    let mut state = State::new();
+    let mut state = State::new(config, store, backend.default_model().to_string());
     let mut stdout = io::stdout();
-    match load_conversation(CONV_FILE) {
-        Ok(conv) => state.conv = conv,
-        Err(err) => {state.append_conv(Role::System, err)}
+    let mut notices = Vec::new();
+    let imported = state.store.import_legacy(Path::new(LEGACY_CONV_FILE)).unwrap_or_else(|err| {
+        notices.push(err);
+        None
+    });
+    if imported.is_some() {
+        notices.push(format!("Imported {LEGACY_CONV_FILE} of the working directory, see /list"));
+    }
+    let last = imported.or(state.store.last()).and_then(|id| match Conversation::recover(&state.store.path(&id)) {
+        Ok((conv, note)) => Some((id, conv, note)),
+        Err(err) => {
+            notices.push(err);
+            None
+        }
+    });
+    match last {
+        Some((id, conv, note)) => {
+            state.switch(id, conv);
+            if let Some(note) = note {
+                notices.push(note);
+                state.save();
+            }
+        }
+        None => state.new_conversation(None),
+    }
+    // NOTE: the command line applies to the conversation opened on startup
+    if let Some(persona) = state.config.persona_arg.as_ref().and_then(|x| state.config.persona(x)).cloned() {
+        state.apply_persona(&persona);
+    }
+    if let Some(model) = state.config.model_arg.clone() {
+        state.conv.model = Some(model);
+    }
+    Tokenizer::for_model(state.model()); // NOTE: starts reading its table before the first message
+    for notice in notices.into_iter().chain(warnings).chain(backend_err) {
+        state.append_conv(Role::Notice, notice);
     }
     let size = terminal::size().unwrap();
     let width = size.0 as usize;
@@ -171,10 +922,12 @@ fn main() -> io::Result<()> {
     let mut ctx = if let Ok(clip_board) = ClipboardContext::new() {
         Some(clip_board)
     } else {
-        state.append_conv(Role::System, "Error: Can't initialize clipboard, copy will not work!".to_string());
+        state.append_conv(Role::Notice, "Error: Can't initialize clipboard, copy will not work!".to_string());
         None
     };
-    let (tx, rx) = mpsc::channel::<String>();
+    let retry = RetryPolicy::from_env();
+    let (models_tx, models_rx) = mpsc::channel::<Result<Vec<String>, GptError>>();
+    let (tx, rx) = mpsc::channel::<(RequestId, StreamEvent)>();
     stdout.queue(terminal::Clear(terminal::ClearType::All))?;
     stdout.queue(event::EnableMouseCapture)?;
     'main: loop {
@@ -187,6 +940,8 @@ fn main() -> io::Result<()> {
                     buffers[1-front].resize(w as usize, h as usize);
                     buffers[front].clear();
                 },
+                event::Event::Key(key) if state.sidebar_key(key) => {}
+                event::Event::Key(key) if state.select_key(key) => {}
                 event::Event::Key(key) => {
                     match key.code {
                         KeyCode::Char(c) => {
@@ -199,13 +954,15 @@ fn main() -> io::Result<()> {
                                             let content = buffers[1-front].get_region_text(&Region::new(start, pos));
                                             if let Some(clip_board) = &mut ctx {
                                                 if let Err(_err) = clip_board.set_contents(content) {
-                                                    state.append_conv(Role::System, "Error: Can't copy text".to_string());
+                                                    state.append_conv(Role::Notice, "Error: Can't copy text".to_string());
                                                 }
                                             }
                                         }
                                     },
+                                    'k' => state.cancel_request(),
+                                    'b' => state.toggle_sidebar(),
                                     'p' => state.view_start += SCROLL_SPEED,
-                                    'n' => if state.view_start >= SCROLL_SPEED { state.view_start -= SCROLL_SPEED; },
+                                    'n' if state.view_start >= SCROLL_SPEED => state.view_start -= SCROLL_SPEED,
                                     _ => {}
                                 },
                                 KeyModifiers::SHIFT => state.input.push(c),
@@ -213,53 +970,73 @@ fn main() -> io::Result<()> {
                                 _ => {}
                             };
                         }
-                        KeyCode::Esc => break 'main,
-                        KeyCode::Enter => {
-                            if state.input.len() > 0 {
-                                state.append_conv(Role::User, state.input.clone());
-                                state.input.clear();
-                                let tx_c = tx.clone();
-                                let conv: Vec<_> = state.conv.iter().map(|(role, content)| serde_json::json!({
-                                    "role": role.value(),
-                                    "content": content,
-                                })).collect();
-                                thread::spawn(move || {
-                                    gpt::prompt(&serde_json::json!(conv.as_slice()), tx_c);
-                                });
-                            }
+                        KeyCode::Esc => {
+                            state.cancel_request();
+                            break 'main;
                         }
-                        KeyCode::Backspace => {
-                            if state.input.len() > 0 {
-                                if key.modifiers == KeyModifiers::ALT {
-                                    let new_len = state.input.trim_end_matches(|x: char| x.is_alphanumeric())
-                                                             .trim_end().len();
-                                    state.input.truncate(new_len);
-                                } else {
-                                    state.input.pop();
+                        KeyCode::Enter if !state.input.is_empty() => {
+                            let input = std::mem::take(&mut state.input);
+                            let edited = state.editing.take();
+                            state.menu_index = 0;
+                            match Command::parse(&input) {
+                                Some(Ok(command)) => state.run_command(command),
+                                Some(Err(err)) => state.append_conv(Role::Notice, err),
+                                None => {
+                                    state.cancel_request(); // NOTE: a new prompt supersedes the one in flight
+                                    if let Some(id) = edited {
+                                        state.fork_at(id);
+                                    }
+                                    state.conv.push(Role::User, input).meta.params = state.once.clone();
+                                    state.save();
+                                    state.send();
                                 }
                             }
                         }
+                        KeyCode::Left if key.modifiers == KeyModifiers::ALT => state.cycle_reply(-1),
+                        KeyCode::Right if key.modifiers == KeyModifiers::ALT => state.cycle_reply(1),
+                        KeyCode::Tab => {
+                            if let Some(entry) = state.completions().get(state.menu_index) {
+                                state.input = entry.clone();
+                                state.menu_index = 0;
+                            }
+                        }
+                        KeyCode::Up if state.menu_index > 0 => state.menu_index -= 1,
+                        KeyCode::Down if state.menu_index + 1 < state.completions().len() => state.menu_index += 1,
+                        KeyCode::Backspace if !state.input.is_empty() => {
+                            if key.modifiers == KeyModifiers::ALT {
+                                let new_len = state.input.trim_end_matches(|x: char| x.is_alphanumeric())
+                                                         .trim_end().len();
+                                state.input.truncate(new_len);
+                            } else {
+                                state.input.pop();
+                            }
+                        }
                         _ => {}
                     }
                 }
                 event::Event::Mouse(mouse_e) => {
+                    let in_sidebar = state.sidebar.is_some() && (mouse_e.column as usize) < sidebar_width(buffers[front].width);
                     match mouse_e.kind {
-                        event::MouseEventKind::Down(btn) => {
-                            if btn == event::MouseButton::Left {
-                                cur_drag = None;
-                                start = (mouse_e.row as i32, mouse_e.column as i32);
-                            }
+                        event::MouseEventKind::Down(event::MouseButton::Left) if in_sidebar => {
+                            state.sidebar_click(mouse_e.row as usize);
                         }
-                        event::MouseEventKind::Up(btn) => {
-                            if btn == event::MouseButton::Left {
-                                on_dragging = false;
+                        event::MouseEventKind::ScrollUp | event::MouseEventKind::ScrollDown if in_sidebar => {
+                            let up = mouse_e.kind == event::MouseEventKind::ScrollUp;
+                            if let Some(sidebar) = &mut state.sidebar {
+                                let index = if up { sidebar.index.saturating_sub(1) } else { sidebar.index + 1 };
+                                sidebar.select(index, &state.conversations);
                             }
                         }
-                        event::MouseEventKind::Drag(btn) => {
-                            if btn == event::MouseButton::Left {
-                                on_dragging = true;
-                                cur_drag = Some((mouse_e.row as i32, mouse_e.column as i32));
-                            }
+                        event::MouseEventKind::Down(event::MouseButton::Left) => {
+                            cur_drag = None;
+                            start = (mouse_e.row as i32, mouse_e.column as i32);
+                        }
+                        event::MouseEventKind::Up(event::MouseButton::Left) => {
+                            on_dragging = false;
+                        }
+                        event::MouseEventKind::Drag(event::MouseButton::Left) => {
+                            on_dragging = true;
+                            cur_drag = Some((mouse_e.row as i32, mouse_e.column as i32));
                         }
                         event::MouseEventKind::ScrollUp => {
                             state.view_start += SCROLL_SPEED;
@@ -270,14 +1047,12 @@ fn main() -> io::Result<()> {
                                 }
                             }
                         }
-                        event::MouseEventKind::ScrollDown => {
-                            if state.view_start >= SCROLL_SPEED {
-                                state.view_start -= SCROLL_SPEED;
-                                if let Some(pos) = &mut cur_drag {
-                                    start.0 -= SCROLL_SPEED as i32;
-                                    if !on_dragging {
-                                        pos.0 -= SCROLL_SPEED as i32;
-                                    }
+                        event::MouseEventKind::ScrollDown if state.view_start >= SCROLL_SPEED => {
+                            state.view_start -= SCROLL_SPEED;
+                            if let Some(pos) = &mut cur_drag {
+                                start.0 -= SCROLL_SPEED as i32;
+                                if !on_dragging {
+                                    pos.0 -= SCROLL_SPEED as i32;
                                 }
                             }
                         }
@@ -287,22 +1062,100 @@ fn main() -> io::Result<()> {
                 _ => {}
             }
         }
-        match rx.try_recv() {
-            Ok(content) => {
-                if content == "[DONE]" {
-                    save_conversation(CONV_FILE, &state.conv);
-                } else if content.starts_with("[START] ") {
-                    let role = content.splitn(2, " ").skip(1).next().unwrap();
-                    state.append_conv(Role::from(role).unwrap(), String::new());
-                } else {
-                    state.conv.last_mut().unwrap().1.push_str(&content);
+        if state.input.starts_with("/model") && matches!(state.models, Models::Unknown) {
+            state.models = Models::Fetching;
+            let backend = backend.clone();
+            let models_tx = models_tx.clone();
+            thread::spawn(move || {
+                let _ = models_tx.send(backend.list_models());
+            });
+        }
+        if let Ok(models) = models_rx.try_recv() {
+            state.models = match models {
+                Ok(models) => Models::Ready(models),
+                Err(err) => Models::Failed(err.to_string()),
+            };
+        }
+        if let Some((id, req)) = state.outgoing.take() {
+            let sink = Sink::new(id, tx.clone());
+            let backend = backend.clone();
+            let cancel = state.cancel.clone();
+            thread::spawn(move || {
+                backend::run(backend.as_ref(), &req, &sink, &cancel, &retry);
+            });
+        }
+        if let Some((id, req)) = state.title_request() {
+            let sink = Sink::new(id, tx.clone());
+            let backend = backend.clone();
+            let retry = RetryPolicy { max_retries: 0, ..retry };
+            thread::spawn(move || {
+                backend::run(backend.as_ref(), &req, &sink, &CancelHandle::new(), &retry);
+            });
+        }
+        while let Ok((id, event)) = rx.try_recv() {
+            if let Some(title) = state.title.as_mut().filter(|x| x.id == id) {
+                match event {
+                    StreamEvent::Delta { text } => title.text.push_str(&text),
+                    StreamEvent::Finished { reason } => state.finish_title(reason),
+                    _ => {}
+                }
+                continue;
+            }
+            if state.request != Some(id) {
+                continue;
+            }
+            match event {
+                StreamEvent::Start { role } => {
+                    state.retry_status = None;
+                    let mut msg = Message::new(state.conv.next_id(), role, String::new());
+                    msg.meta.model = Some(state.model().to_string());
+                    let regenerating = state.regenerating.take();
+                    if let Some(i) = state.conv.messages.iter().position(|x| Some(x.id) == regenerating) {
+                        state.conv.fork(i);
+                    }
+                    state.reply = Some(msg.id);
+                    state.conv.messages.push(msg);
+                }
+                StreamEvent::Delta { text } => {
+                    if let Some(msg) = state.reply_mut() {
+                        msg.content.push_str(&text);
+                    }
                     state.view_start = 0;
                 }
+                StreamEvent::Usage(usage) => {
+                    if let Some(msg) = state.reply_mut() {
+                        msg.meta.usage = Some(usage);
+                    }
+                }
+                StreamEvent::Error { kind, message } => {
+                    state.append_conv(Role::Notice, format!("{kind}: {message}"));
+                    state.view_start = 0;
+                }
+                StreamEvent::Retrying { attempt, max_retries, delay, reason } => {
+                    let status = format!("({attempt}/{max_retries}) {reason}");
+                    state.retry_status = Some((status, Instant::now() + delay));
+                }
+                StreamEvent::Finished { reason } => state.finish_request(reason),
             }
-            Err(_err) => {}
+        }
+        if state.request.is_some() && state.last_save.elapsed() > AUTOSAVE_INTERVAL {
+            state.save(); // NOTE: a crash mid reply loses a few seconds of it at most
         }
         let buffer = &mut buffers[front];
-        render_conversation(&mut state, buffer, buffer.height-3, buffer.height - 2, buffer.width);
+        let (side_rect, main_rect) = match state.sidebar {
+            Some(_) => buffer.rect().split_left(sidebar_width(buffer.width)),
+            None => buffer.rect().split_left(0),
+        };
+        if let Some(sidebar) = &mut state.sidebar {
+            sidebar.render(&state.conversations, &state.conv_id, &mut buffer.view(side_rect));
+        }
+        let (conv_rect, input_rect) = main_rect.split_bottom(2);
+        let (header_rect, conv_rect) = conv_rect.split_top(1);
+        render_header(&state, &mut buffer.view(header_rect));
+        render_conversation(&mut state, &mut buffer.view(conv_rect));
+        let completions = state.completions();
+        state.menu_index = state.menu_index.min(completions.len().saturating_sub(1));
+        render_menu(&state, &completions, &mut buffer.view(conv_rect), conv_rect.height.saturating_sub(1));
         if let Some(pos) = cur_drag {
             let start = Position::new(start.0.max(0) as usize, start.1 as usize);
             let pos = Position::new(pos.0.max(0) as usize, pos.1 as usize);
@@ -312,10 +1165,21 @@ fn main() -> io::Result<()> {
         if input_line.len() < buffer.width {
             input_line.push_str(&" ".repeat(buffer.width - input_line.len()));
         }
-        buffer.put_line(buffer.height-2, Some(INPUT_COLOR), Some(DEFAULT_BG), &"—".repeat(buffer.width));
-        buffer.put_line(buffer.height-1, Some(INPUT_COLOR), Some(DEFAULT_BG), &input_line);
+        let separator = match &state.retry_status {
+            Some((status, deadline)) => {
+                let secs = deadline.saturating_duration_since(Instant::now()).as_secs_f64().ceil();
+                format!("—— retrying in {secs}s {status} {}", "—".repeat(buffer.width))
+            }
+            None => match state.edit_status().or_else(|| state.usage_status()) {
+                Some(status) => format!("—— {status} {}", "—".repeat(buffer.width)),
+                None => "—".repeat(buffer.width),
+            },
+        };
+        let mut input_view = buffer.view(input_rect);
+        input_view.put_line(0, Some(INPUT_COLOR), Some(DEFAULT_BG), &separator);
+        input_view.put_line(1, Some(INPUT_COLOR), Some(DEFAULT_BG), &input_line);
         render_diff(&mut stdout, &buffers[front], &buffers[1-front])?;
-        stdout.queue(cursor::MoveTo(state.input.len() as u16, buffers[front].height as u16 - 1))?;
+        stdout.queue(cursor::MoveTo((input_rect.col + state.input.len()) as u16, buffers[front].height as u16 - 1))?;
         stdout.flush()?;
         thread::sleep(Duration::from_millis(1000/60));
 
@@ -0,0 +1,97 @@
+use serde_json::Value;
+
+/// Names of the parameters, as typed in `/set` and sent to openai style APIs.
+pub const NAMES: &[&str] = &["temperature", "top_p", "max_tokens", "stop", "seed"];
+
+/// Generation parameters, anything left unset is up to the provider.
+/// They come from the config, the conversation and the next message only, each
+/// layer overriding the one before, see [`Params::merge`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<u64>,
+    /// Sequences that end the reply when the model produces them.
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<u64>,
+}
+
+fn parse_range(name: &str, value: &str, max: f64) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(x) if (0.0..=max).contains(&x) => Ok(x),
+        _ => Err(format!("{name} must be a number between 0 and {max}")),
+    }
+}
+
+impl Params {
+    pub fn is_empty(&self) -> bool {
+        *self == Params::default()
+    }
+    /// `self` with whatever `other` sets taking precedence.
+    pub fn merge(&self, other: &Params) -> Params {
+        Params {
+            temperature: other.temperature.or(self.temperature),
+            top_p: other.top_p.or(self.top_p),
+            max_tokens: other.max_tokens.or(self.max_tokens),
+            stop: other.stop.clone().or(self.stop.clone()),
+            seed: other.seed.or(self.seed),
+        }
+    }
+    /// Sets `name` from user input, `None` unsets it. Stop sequences are given as
+    /// a single sequence or a json array of them.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        let value = value.map(|x| x.trim());
+        match (name, value) {
+            ("temperature", None) => self.temperature = None,
+            ("temperature", Some(x)) => self.temperature = Some(parse_range(name, x, 2.0)?),
+            ("top_p", None) => self.top_p = None,
+            ("top_p", Some(x)) => self.top_p = Some(parse_range(name, x, 1.0)?),
+            ("max_tokens", None) => self.max_tokens = None,
+            ("max_tokens", Some(x)) => {
+                self.max_tokens = Some(x.parse().ok().filter(|x| *x > 0).ok_or("max_tokens must be a positive integer")?);
+            }
+            ("stop", None) => self.stop = None,
+            ("stop", Some(x)) if x.starts_with('[') => {
+                self.stop = Some(serde_json::from_str(x).map_err(|_err| "stop must be a json array of strings")?);
+            }
+            ("stop", Some(x)) => self.stop = Some(vec![x.to_string()]),
+            ("seed", None) => self.seed = None,
+            ("seed", Some(x)) => self.seed = Some(x.parse().map_err(|_err| "seed must be a non negative integer")?),
+            _ => return Err(format!("Unknown parameter {name}, expected one of {}", NAMES.join(", "))),
+        }
+        Ok(())
+    }
+    /// The parameters that are set, with their openai names.
+    pub fn to_json(&self) -> Value {
+        let mut data = serde_json::Map::new();
+        if let Some(x) = self.temperature { data.insert("temperature".to_string(), x.into()); }
+        if let Some(x) = self.top_p { data.insert("top_p".to_string(), x.into()); }
+        if let Some(x) = self.max_tokens { data.insert("max_tokens".to_string(), x.into()); }
+        if let Some(x) = &self.stop { data.insert("stop".to_string(), x.clone().into()); }
+        if let Some(x) = self.seed { data.insert("seed".to_string(), x.into()); }
+        Value::Object(data)
+    }
+    /// Reads what [`Params::to_json`] wrote, values of the wrong type are skipped.
+    pub fn from_json(data: &Value) -> Params {
+        Params {
+            temperature: data["temperature"].as_f64(),
+            top_p: data["top_p"].as_f64(),
+            max_tokens: data["max_tokens"].as_u64(),
+            stop: data["stop"].as_array().map(|x| x.iter().filter_map(|x| x.as_str()).map(|x| x.to_string()).collect()),
+            seed: data["seed"].as_u64(),
+        }
+    }
+}
+
+impl std::fmt::Display for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("provider defaults");
+        }
+        let data = self.to_json();
+        let params: Vec<_> = data.as_object().into_iter().flatten()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        f.write_str(&params.join(" "))
+    }
+}
@@ -35,6 +35,62 @@ impl Region {
     }
 }
 
+/// A rectangle of the screen, drawn into through a [`View`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect { pub row: usize, pub col: usize, pub width: usize, pub height: usize }
+impl Rect {
+    /// Splits off the `width` leftmost columns.
+    pub fn split_left(&self, width: usize) -> (Rect, Rect) {
+        let width = width.min(self.width);
+        (Rect { width, ..*self }, Rect { col: self.col + width, width: self.width - width, ..*self })
+    }
+    /// Splits off the `height` top rows.
+    pub fn split_top(&self, height: usize) -> (Rect, Rect) {
+        let height = height.min(self.height);
+        (Rect { height, ..*self }, Rect { row: self.row + height, height: self.height - height, ..*self })
+    }
+    /// Splits off the `height` bottom rows.
+    pub fn split_bottom(&self, height: usize) -> (Rect, Rect) {
+        let height = height.min(self.height);
+        (Rect { height: self.height - height, ..*self }, Rect { row: self.row + self.height - height, height, ..*self })
+    }
+}
+
+/// Draws into one [`Rect`] of a buffer with coordinates relative to it,
+/// whatever falls outside is clipped.
+pub struct View<'a> {
+    buffer: &'a mut Buffer,
+    pub rect: Rect,
+}
+impl View<'_> {
+    pub fn width(&self) -> usize {
+        self.rect.width
+    }
+    pub fn height(&self) -> usize {
+        self.rect.height
+    }
+    pub fn put_line(&mut self, line: usize, fore: Option<Color>, back: Option<Color>, data: &str) {
+        self.put(line, 0, fore, back, data);
+    }
+    /// Writes `data` from column `col` of `line`.
+    pub fn put(&mut self, line: usize, col: usize, fore: Option<Color>, back: Option<Color>, data: &str) {
+        if line >= self.rect.height {
+            return;
+        }
+        let row = &mut self.buffer.data[self.rect.row + line];
+        for (i, c) in data.chars().take(self.rect.width.saturating_sub(col)).enumerate() {
+            let cell = &mut row[self.rect.col + col + i];
+            cell.c = c;
+            if let Some(fg) = fore {
+                cell.fg = fg;
+            }
+            if let Some(bg) = back {
+                cell.bg = bg;
+            }
+        }
+    }
+}
+
 pub const DEFAULT_BG: Color = Color::Reset;
 pub const DEFAULT_FG: Color = Color::White;
 
@@ -73,21 +129,17 @@ impl Buffer {
             }
         }
     }
+    pub fn rect(&self) -> Rect {
+        Rect { row: 0, col: 0, width: self.width, height: self.height }
+    }
+    /// The part of the buffer covered by `rect`, which must fit in it.
+    pub fn view(&mut self, rect: Rect) -> View<'_> {
+        assert!(rect.row + rect.height <= self.height && rect.col + rect.width <= self.width);
+        View { buffer: self, rect }
+    }
     pub fn get(&self, r: usize, c: usize) -> Cell {
         self.data[r][c]
     }
-    pub fn put_line(&mut self, line: usize, fore: Option<Color>, back: Option<Color>, data: &str) {
-        let mut chars = data.chars();
-        for i in 0..self.width.min(data.len()) {
-            self.data[line][i].c = chars.next().unwrap_or(' ');
-            if let Some(fg) = fore {
-                self.data[line][i].fg = fg;
-            }
-            if let Some(bg) = back {
-                self.data[line][i].bg = bg;
-            }
-        }
-    }
     pub fn resize(&mut self, w: usize, h: usize) {
         self.width = w;
         self.height = h;
@@ -104,7 +156,7 @@ impl Buffer {
                     result.push(self.data[r][c].c);
                 }
             }
-            result = result.trim_end_matches(|x| x == ' ').to_string() + "\n";
+            result = result.trim_end_matches(' ').to_string() + "\n";
         }
         result
     }
@@ -0,0 +1,91 @@
+use std::time::SystemTime;
+use crossterm::style::Color;
+use crate::renderer::{View, DEFAULT_FG};
+use crate::store::Entry;
+
+const SELECTED_BG: Color = Color::DarkGrey;
+const DETAIL_COLOR: Color = Color::Grey;
+const CURRENT_COLOR: Color = Color::Green;
+const FILTER_COLOR: Color = Color::Yellow;
+/// Rows above the list: the filter and a separator.
+const HEADER_HEIGHT: usize = 2;
+/// Rows per conversation: the title, then when it was modified, its length and model.
+const ENTRY_HEIGHT: usize = 2;
+
+/// "3m ago", "5h ago"...
+fn ago(time: SystemTime) -> String {
+    let secs = SystemTime::now().duration_since(time).unwrap_or_default().as_secs();
+    match secs {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// The conversation browser on the left, it takes the keyboard while open:
+/// typing filters, Up/Down select, Enter opens, Delete twice deletes.
+#[derive(Debug, Clone, Default)]
+pub struct Sidebar {
+    pub filter: String,
+    /// Selected entry among those matching the filter.
+    pub index: usize,
+    /// First entry shown, so the selected one stays in view.
+    scroll: usize,
+    /// Delete was pressed once on the selected entry.
+    pub confirm_delete: bool,
+}
+
+impl Sidebar {
+    /// Entries whose name contains the filter, ignoring case.
+    pub fn matches<'a>(&self, entries: &'a [Entry]) -> Vec<&'a Entry> {
+        let filter = self.filter.to_lowercase();
+        entries.iter().filter(|x| x.name().to_lowercase().contains(&filter)).collect()
+    }
+    pub fn selected<'a>(&self, entries: &'a [Entry]) -> Option<&'a Entry> {
+        self.matches(entries).get(self.index).copied()
+    }
+    pub fn select(&mut self, index: usize, entries: &[Entry]) {
+        self.index = index.min(self.matches(entries).len().saturating_sub(1));
+        self.confirm_delete = false;
+    }
+    /// Index of the entry drawn on `row` of the view, see [`Sidebar::render`].
+    pub fn entry_at(&self, row: usize) -> Option<usize> {
+        row.checked_sub(HEADER_HEIGHT).map(|x| self.scroll + x / ENTRY_HEIGHT)
+    }
+    pub fn render(&mut self, entries: &[Entry], current: &str, view: &mut View) {
+        let matches = self.matches(entries);
+        let width = view.width();
+        let fill = |text: String| format!("{text}{}", " ".repeat(width));
+        view.put_line(0, Some(FILTER_COLOR), None, &fill(format!(" filter: {}", self.filter)));
+        view.put_line(1, Some(DETAIL_COLOR), None, &"─".repeat(width));
+        let shown = (view.height().saturating_sub(HEADER_HEIGHT) / ENTRY_HEIGHT).max(1);
+        if self.index < self.scroll {
+            self.scroll = self.index;
+        } else if self.index >= self.scroll + shown {
+            self.scroll = self.index + 1 - shown;
+        }
+        if matches.is_empty() {
+            view.put_line(HEADER_HEIGHT, Some(DETAIL_COLOR), None, " no conversations");
+        }
+        for (i, entry) in matches.iter().enumerate().skip(self.scroll).take(shown) {
+            let row = HEADER_HEIGHT + (i - self.scroll) * ENTRY_HEIGHT;
+            let bg = if i == self.index { Some(SELECTED_BG) } else { None };
+            let fg = if entry.id == current { CURRENT_COLOR } else { DEFAULT_FG };
+            let title = match (i == self.index, self.confirm_delete) {
+                (true, true) => "press Delete again to delete".to_string(),
+                _ => entry.name().to_string(),
+            };
+            let mut details = format!("{}, {} msgs", ago(entry.modified), entry.messages);
+            if let Some(model) = &entry.model {
+                details.push_str(&format!(", {model}"));
+            }
+            view.put_line(row, Some(fg), bg, &fill(format!(" {title}")));
+            view.put_line(row + 1, Some(DETAIL_COLOR), bg, &fill(format!("   {details}")));
+        }
+        // NOTE: the right border keeps the pane apart from the conversation
+        for row in 0..view.height() {
+            view.put(row, width.saturating_sub(1), Some(DETAIL_COLOR), Some(crate::renderer::DEFAULT_BG), "│");
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+/// One dispatched server-sent event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Value of the `event:` field, "message" when the server did not name it.
+    pub event: String,
+    /// The `data:` fields of the event joined with "\n".
+    pub data: String,
+    /// Last event id seen on the stream, if any.
+    pub id: Option<String>,
+}
+
+/// Incremental decoder for the `text/event-stream` format, following
+/// https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation.
+/// Bytes can be fed in arbitrary chunks, events that straddle reads are only
+/// dispatched once their terminating blank line has arrived.
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    /// A chunk ended on '\r', a '\n' at the start of the next one belongs to the same line ending.
+    pending_cr: bool,
+    started: bool,
+    event: String,
+    data: String,
+    last_event_id: Option<String>,
+    /// Reconnection time requested by the server with a `retry:` field.
+    pub retry: Option<Duration>,
+}
+
+impl Decoder {
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Event> {
+        let mut chunk = chunk;
+        if self.pending_cr && !chunk.is_empty() {
+            self.pending_cr = false;
+            if chunk[0] == b'\n' {
+                chunk = &chunk[1..];
+            }
+        }
+        self.buf.extend_from_slice(chunk);
+        if !self.started { // NOTE: a leading byte order mark is ignored
+            if self.buf.len() < 3 && b"\xEF\xBB\xBF".starts_with(&self.buf) {
+                return Vec::new();
+            }
+            if self.buf.starts_with(b"\xEF\xBB\xBF") {
+                self.buf.drain(..3);
+            }
+            self.started = true;
+        }
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buf.len() {
+            match self.buf[i] {
+                b'\n' => {}
+                b'\r' if i + 1 == self.buf.len() => self.pending_cr = true,
+                b'\r' => {}
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            }
+            // NOTE: a line is only decoded once complete, so multibyte characters never get split
+            let line = String::from_utf8_lossy(&self.buf[start..i]).into_owned();
+            if let Some(event) = self.on_line(&line) {
+                events.push(event);
+            }
+            if self.buf[i] == b'\r' && self.buf.get(i + 1) == Some(&b'\n') {
+                i += 1;
+            }
+            i += 1;
+            start = i;
+        }
+        self.buf.drain(..start);
+        events
+    }
+
+    fn on_line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') { // NOTE: comment, used as keep-alive
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => self.last_event_id = Some(value.to_string()),
+            "retry" if !value.is_empty() && value.bytes().all(|x| x.is_ascii_digit()) => {
+                self.retry = value.parse().ok().map(Duration::from_millis);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        let event = std::mem::take(&mut self.event);
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut data = std::mem::take(&mut self.data);
+        data.pop();
+        Some(Event {
+            event: if event.is_empty() { "message".to_string() } else { event },
+            data,
+            id: self.last_event_id.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &[u8] = b"\xEF\xBB\xBF: keep-alive\r\nretry: 1500\r\n\r\nevent: delta\r\nid: 7\r\ndata: first\r\ndata:  second \xC3\xB6\r\n\r\n\
+        data: {\"x\":1}\n\ndata: cr\r\rretry: soon\r\n";
+
+    fn expected() -> Vec<Event> {
+        vec![
+            Event { event: "delta".to_string(), data: "first\n second ö".to_string(), id: Some("7".to_string()) },
+            Event { event: "message".to_string(), data: "{\"x\":1}".to_string(), id: Some("7".to_string()) },
+            Event { event: "message".to_string(), data: "cr".to_string(), id: Some("7".to_string()) },
+        ]
+    }
+
+    #[test]
+    fn split_anywhere() {
+        for i in 0..=STREAM.len() {
+            let mut decoder = Decoder::default();
+            let mut events = decoder.feed(&STREAM[..i]);
+            events.extend(decoder.feed(&STREAM[i..]));
+            assert_eq!(events, expected(), "split at {i}");
+            assert_eq!(decoder.retry, Some(Duration::from_millis(1500)), "split at {i}");
+        }
+        let mut decoder = Decoder::default();
+        let events: Vec<Event> = STREAM.chunks(1).flat_map(|x| decoder.feed(x)).collect();
+        assert_eq!(events, expected());
+    }
+
+    #[test]
+    fn crlf_split_across_feeds() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.feed(b"data: a\r").is_empty());
+        assert!(decoder.feed(b"\n").is_empty());
+        // NOTE: had the '\n' counted as a line of its own the event would be out already
+        assert!(decoder.feed(b"data: b\r").is_empty());
+        let events = decoder.feed(b"\n\r\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "a\nb");
+    }
+
+    #[test]
+    fn bom_only_at_start() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.feed(b"\xEF").is_empty());
+        assert!(decoder.feed(b"\xBB").is_empty());
+        let events = decoder.feed(b"\xBFdata: x\n\n\xEF\xBB\xBFdata: y\n\n");
+        assert_eq!(events.len(), 1, "{events:?}");
+        assert_eq!(events[0].data, "x");
+    }
+}
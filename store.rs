@@ -0,0 +1,148 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::conversation::{self, Conversation};
+
+/// One conversation of the store, as listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// File name without `.json`, never changes.
+    pub id: String,
+    pub title: Option<String>,
+    pub modified: SystemTime,
+    pub model: Option<String>,
+    /// Number of messages, notices included.
+    pub messages: usize,
+}
+impl Entry {
+    /// What the user calls the conversation: its title, or its id for untitled ones.
+    pub fn name(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// Conversations saved as `<id>.json` in one directory, usually `<data dir>/conversations`,
+/// along with a `last` file holding the id of the one opened last.
+pub struct Store {
+    dir: PathBuf,
+    /// Entries listed before by id, with the size of their file, see [`Store::list`].
+    cache: RefCell<HashMap<String, (u64, Entry)>>,
+}
+
+impl Store {
+    pub fn open(dir: PathBuf) -> Result<Self, String> {
+        std::fs::create_dir_all(&dir).map_err(|err| format!("Could not create {}: {err}", dir.display()))?;
+        Ok(Store { dir, cache: RefCell::default() })
+    }
+    pub fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+    /// An id no conversation has yet, from the current time.
+    pub fn new_id(&self) -> String {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut id = secs.to_string();
+        let mut n = 1;
+        while self.path(&id).exists() {
+            n += 1;
+            id = format!("{secs}-{n}");
+        }
+        id
+    }
+    /// All conversations, the most recently modified first. Only the files that
+    /// changed since the last call are read.
+    pub fn list(&self) -> Vec<Entry> {
+        let Ok(dir) = std::fs::read_dir(&self.dir) else { return Vec::new() };
+        let mut cache = self.cache.borrow_mut();
+        let fresh: HashMap<String, (u64, Entry)> = dir.filter_map(|x| x.ok())
+            .filter_map(|x| {
+                let path = x.path();
+                let id = path.file_name()?.to_str()?.strip_suffix(".json")?.to_string();
+                let metadata = x.metadata().ok();
+                let modified = metadata.as_ref().and_then(|x| x.modified().ok()).unwrap_or(UNIX_EPOCH);
+                let len = metadata.map_or(0, |x| x.len());
+                if let Some(cached) = cache.remove(&id).filter(|(x, entry)| *x == len && entry.modified == modified) {
+                    return Some((id, cached));
+                }
+                // NOTE: files that can't be read are listed too, opening them tells what is wrong
+                let conv = Conversation::load(&path).unwrap_or_default();
+                let entry = Entry { id: id.clone(), title: conv.title, modified, model: conv.model, messages: conv.messages.len() };
+                Some((id, (len, entry)))
+            })
+            .collect();
+        *cache = fresh;
+        let mut entries: Vec<Entry> = cache.values().map(|(_, x)| x.clone()).collect();
+        entries.sort_by(|a, b| b.modified.cmp(&a.modified).then(b.id.cmp(&a.id)));
+        entries
+    }
+    /// The conversation called `name`, or with id `name`.
+    pub fn find(&self, name: &str) -> Option<Entry> {
+        let entries = self.list();
+        entries.iter().find(|x| x.title.as_deref() == Some(name))
+            .or_else(|| entries.iter().find(|x| x.id == name))
+            .cloned()
+    }
+    pub fn last(&self) -> Option<String> {
+        let id = std::fs::read_to_string(self.dir.join("last")).ok()?;
+        Some(id.trim().to_string()).filter(|x| self.path(x).exists())
+    }
+    pub fn set_last(&self, id: &str) {
+        // NOTE: only costs reopening another conversation next time, not worth bothering the user
+        let _ = std::fs::write(self.dir.join("last"), id);
+    }
+    /// Deletes the conversation along with its backups.
+    pub fn delete(&self, id: &str) -> Result<(), String> {
+        std::fs::remove_file(self.path(id)).map_err(|err| format!("Could not delete {id}: {err}"))?;
+        for backup in conversation::backups(&self.path(id)) {
+            let _ = std::fs::remove_file(backup);
+        }
+        Ok(())
+    }
+    /// Moves the `conversation.json` older versions kept in the working directory
+    /// into the store, titled after the directory. The file is renamed to
+    /// `conversation.json.imported` so it is imported once.
+    pub fn import_legacy(&self, path: &Path) -> Result<Option<String>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut conv = Conversation::load(path)?;
+        if conv.title.is_none() {
+            let dir = std::env::current_dir().ok();
+            conv.title = dir.as_deref().and_then(|x| x.file_name()).map(|x| x.to_string_lossy().into_owned());
+            if conv.title.as_deref().is_some_and(|x| self.find(x).is_some()) {
+                conv.title = None;
+            }
+        }
+        let id = self.new_id();
+        conv.save(&self.path(&id))?;
+        let mut imported = path.as_os_str().to_owned();
+        imported.push(".imported");
+        std::fs::rename(path, &imported).map_err(|err| format!("Could not rename {}: {err}", path.display()))?;
+        Ok(Some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Role;
+
+    #[test]
+    fn list_changes() {
+        let dir = std::env::temp_dir().join(format!("gpterm-store-{}", std::process::id()));
+        let store = Store::open(dir.clone()).unwrap();
+        let mut conv = Conversation { title: Some("rust".to_string()), ..Default::default() };
+        conv.push(Role::User, "hi".to_string());
+        conv.save(&store.path("1")).unwrap();
+        Conversation::default().save(&store.path("2")).unwrap();
+        let names = |store: &Store| store.list().iter().map(|x| (x.name().to_string(), x.messages)).collect::<Vec<_>>();
+        assert_eq!(names(&store), [("2".to_string(), 0), ("rust".to_string(), 1)]);
+
+        conv.title = Some("rust questions".to_string());
+        conv.push(Role::AI, "hello".to_string());
+        conv.save(&store.path("1")).unwrap();
+        store.delete("2").unwrap();
+        assert_eq!(names(&store), [("rust questions".to_string(), 2)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Once, OnceLock};
+use crate::config;
+
+/// Byte pair encoding with the merge ranks of a tiktoken encoding, compiled in from
+/// `tables/` when it was there at build time or loaded from `<data dir>/<name>.tiktoken`
+/// (`https://openaipublic.blob.core.windows.net/encodings/<name>.tiktoken`).
+pub struct Bpe {
+    ranks: HashMap<Vec<u8>, u32>,
+    pattern: Pattern,
+}
+
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in data.bytes().take_while(|x| *x != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+impl Bpe {
+    /// Parses a tiktoken file, one `<base64 token> <rank>` per line, for text split with `pattern`.
+    pub fn parse(data: &str, pattern: Pattern) -> Result<Self, String> {
+        let mut ranks = HashMap::new();
+        for (i, line) in data.lines().enumerate().filter(|(_, x)| !x.is_empty()) {
+            let token = line.split_once(' ')
+                .and_then(|(token, rank)| Some((base64_decode(token)?, rank.trim().parse().ok()?)));
+            match token {
+                Some((token, rank)) => ranks.insert(token, rank),
+                None => return Err(format!("Invalid token on line {}", i + 1)),
+            };
+        }
+        Ok(Bpe { ranks, pattern })
+    }
+    pub fn load(path: &Path, pattern: Pattern) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path).map_err(|err| format!("Could not read {}: {err}", path.display()))?;
+        Bpe::parse(&data, pattern)
+    }
+    /// Number of tokens `piece` is encoded to: its bytes are merged pairwise,
+    /// lowest rank first, until no adjacent pair is a known token.
+    fn count_piece(&self, piece: &[u8]) -> usize {
+        if self.ranks.contains_key(piece) {
+            return 1;
+        }
+        // NOTE: boundaries of the parts, the piece starts as single bytes
+        let mut parts: Vec<usize> = (0..=piece.len()).collect();
+        loop {
+            let best = (0..parts.len().saturating_sub(2))
+                .filter_map(|i| self.ranks.get(&piece[parts[i]..parts[i + 2]]).map(|rank| (*rank, i)))
+                .min();
+            match best {
+                Some((_, i)) => { parts.remove(i + 1); }
+                None => return parts.len() - 1,
+            }
+        }
+    }
+}
+
+/// `\p{L}`, roman numerals and the like are alphabetic but numbers.
+fn is_letter(c: char) -> bool {
+    c.is_alphabetic() && !c.is_numeric()
+}
+/// `\p{Lt}`, letters like ǅ that are neither upper nor lowercase.
+fn is_titlecase(c: char) -> bool {
+    matches!(c, '\u{1C5}' | '\u{1C8}' | '\u{1CB}' | '\u{1F2}' | '\u{1F88}'..='\u{1F8F}' | '\u{1F98}'..='\u{1F9F}'
+        | '\u{1FA8}'..='\u{1FAF}' | '\u{1FBC}' | '\u{1FCC}' | '\u{1FFC}')
+}
+/// `[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]` of o200k, letters without case count as both.
+fn is_upper(c: char) -> bool {
+    is_letter(c) && !c.is_lowercase()
+}
+/// `[\p{Ll}\p{Lm}\p{Lo}\p{M}]` of o200k.
+fn is_lower(c: char) -> bool {
+    is_letter(c) && !c.is_uppercase() && !is_titlecase(c)
+}
+fn is_number(c: char) -> bool {
+    c.is_numeric()
+}
+fn is_newline(c: char) -> bool {
+    c == '\r' || c == '\n'
+}
+fn is_punct(c: char) -> bool {
+    !c.is_whitespace() && !is_letter(c) && !is_number(c)
+}
+
+/// Pretokenizer of an encoding, the regex that cuts text into the pieces that get
+/// encoded separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// Contractions, words with one leading non letter, up to 3 digits, punctuation
+    /// runs with an optional leading space, and whitespace, the last space before a
+    /// word staying with the word.
+    Cl100k,
+    /// Like cl100k, but words are also cut before an uppercase letter following a
+    /// lowercase one and keep their contraction.
+    O200k,
+}
+
+/// Splits `text` into the pieces of `pattern`. Unicode categories are approximated
+/// with the properties of `char`, combining marks and modifier letters can end up
+/// on the wrong side of a cut.
+pub fn split(text: &str, pattern: Pattern) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let at = |i: usize| chars.get(i).map(|x| x.1);
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i].1;
+        let mut end = i + 1;
+        // NOTE: length of the contraction starting at `j`, if there is one
+        let contraction = |j: usize| {
+            if at(j) != Some('\'') {
+                return None;
+            }
+            let suffix = ["s", "t", "re", "ve", "m", "ll", "d"].iter().find(|x| {
+                x.chars().enumerate().all(|(k, y)| at(j + 1 + k).map(|z| z.to_ascii_lowercase()) == Some(y))
+            })?;
+            Some(suffix.len() + 1)
+        };
+        let word = is_letter(c) || (!is_newline(c) && !is_number(c) && at(i + 1).is_some_and(is_letter));
+        if let (Some(len), Pattern::Cl100k) = (contraction(i), pattern) {
+            end = i + len;
+        } else if word && pattern == Pattern::Cl100k {
+            while at(end).is_some_and(is_letter) { end += 1; }
+        } else if word {
+            // NOTE: `U*L+` backtracks into the uppercase run for a letter without case
+            // to start the lowercase run at, `U+L*` is left when there is none
+            let start = if is_letter(c) { i } else { i + 1 };
+            let mut upper = start;
+            while at(upper).is_some_and(is_upper) { upper += 1; }
+            end = (start..=upper).rev().find(|x| at(*x).is_some_and(is_lower)).unwrap_or(upper);
+            while at(end).is_some_and(is_lower) { end += 1; }
+            end += contraction(end).unwrap_or_default();
+        } else if is_number(c) {
+            while end < i + 3 && at(end).is_some_and(is_number) { end += 1; }
+        } else if is_punct(c) || (c == ' ' && at(i + 1).is_some_and(is_punct)) {
+            while at(end).is_some_and(is_punct) { end += 1; }
+            while at(end).is_some_and(|x| is_newline(x) || (x == '/' && pattern == Pattern::O200k)) { end += 1; }
+        } else {
+            while at(end).is_some_and(char::is_whitespace) { end += 1; }
+            if let Some(newline) = (i..end).rev().find(|x| is_newline(chars[*x].1)) {
+                end = newline + 1;
+            } else if end < chars.len() && end - i > 1 {
+                end -= 1;
+            }
+        }
+        let start = chars[i].0;
+        let stop = chars.get(end).map_or(text.len(), |x| x.0);
+        pieces.push(&text[start..stop]);
+        i = end;
+    }
+    pieces
+}
+
+/// Table of the encoding `name` compiled into the binary, from `tables/<name>.tiktoken`.
+#[cfg(bundled_tables)]
+fn bundled(name: &str) -> Option<&'static str> {
+    match name {
+        "cl100k_base" => Some(include_str!("tables/cl100k_base.tiktoken")),
+        "o200k_base" => Some(include_str!("tables/o200k_base.tiktoken")),
+        _ => None,
+    }
+}
+#[cfg(not(bundled_tables))]
+fn bundled(_name: &str) -> Option<&'static str> {
+    None
+}
+
+/// Pieces longer than this are estimated, merging them takes quadratic time and a
+/// long run of letters pasted in would hold up the UI.
+const MAX_PIECE: usize = 64;
+
+/// A tiktoken table, loaded once in the background, see [`Tokenizer::for_model`].
+struct Table {
+    name: &'static str,
+    pattern: Pattern,
+    loading: Once,
+    bpe: OnceLock<Option<Arc<Bpe>>>,
+}
+impl Table {
+    const fn new(name: &'static str, pattern: Pattern) -> Self {
+        Table { name, pattern, loading: Once::new(), bpe: OnceLock::new() }
+    }
+    fn load(&self) -> Option<Arc<Bpe>> {
+        if let Some(data) = bundled(self.name) {
+            return Bpe::parse(data, self.pattern).ok().map(Arc::new);
+        }
+        let path = config::data_dir()?.join(format!("{}.tiktoken", self.name));
+        Bpe::load(&path, self.pattern).ok().map(Arc::new)
+    }
+}
+
+/// Counts tokens with the model's encoding when its table is installed, else
+/// estimates them from the length of the pieces.
+#[derive(Clone)]
+pub enum Tokenizer {
+    Bpe(Arc<Bpe>),
+    /// About 4 bytes per token, on the high side for english text.
+    Estimate,
+}
+
+impl Tokenizer {
+    pub fn count(&self, text: &str) -> usize {
+        match self {
+            Tokenizer::Bpe(bpe) => split(text, bpe.pattern).into_iter()
+                .map(|x| if x.len() > MAX_PIECE { x.len().div_ceil(4) } else { bpe.count_piece(x.as_bytes()) })
+                .sum(),
+            Tokenizer::Estimate => split(text, Pattern::Cl100k).into_iter().map(|x| x.len().div_ceil(4)).sum(),
+        }
+    }
+    /// Tokenizer of `model`: o200k_base for the gpt-4o generation and later,
+    /// cl100k_base for the rest, which is close enough for models of other providers.
+    /// The table is read in the background the first time, until then this estimates.
+    pub fn for_model(model: &str) -> Self {
+        static CL100K: Table = Table::new("cl100k_base", Pattern::Cl100k);
+        static O200K: Table = Table::new("o200k_base", Pattern::O200k);
+        let o200k = ["gpt-4o", "gpt-4.1", "gpt-4.5", "gpt-5", "o1", "o3", "o4", "chatgpt-4o"].iter().any(|x| model.starts_with(x));
+        let table = if o200k { &O200K } else { &CL100K };
+        table.loading.call_once(|| {
+            std::thread::spawn(|| { table.bpe.get_or_init(|| table.load()); });
+        });
+        match table.bpe.get() {
+            Some(Some(bpe)) => Tokenizer::Bpe(bpe.clone()),
+            _ => Tokenizer::Estimate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a b c d, with bc merged before ab before cd.
+    const RANKS: &str = "YQ== 0\nYg== 1\nYw== 2\nZA== 3\nYmM= 4\nYWI= 5\nY2Q= 6\n";
+
+    #[test]
+    fn count_piece() {
+        let bpe = Bpe::parse(RANKS, Pattern::Cl100k).unwrap();
+        assert_eq!(bpe.count_piece(b"bc"), 1);
+        assert_eq!(bpe.count_piece(b"abab"), 2);
+        // NOTE: ab + cd would be 2, but bc has the lowest rank and goes first
+        assert_eq!(bpe.count_piece(b"abcd"), 3);
+        assert_eq!(bpe.count_piece(b"zz"), 2);
+        assert_eq!(bpe.count_piece(b""), 0);
+        let tokenizer = Tokenizer::Bpe(Arc::new(bpe));
+        assert_eq!(tokenizer.count("ab cd"), 3);
+        assert_eq!(tokenizer.count(&"ab".repeat(MAX_PIECE)), MAX_PIECE / 2);
+        assert!(Bpe::parse("YQ== 0\nYg==\n", Pattern::Cl100k).is_err());
+    }
+
+    // NOTE: expected pieces are those of the tiktoken regexes
+    const CASES: [&str; 7] = [
+        "Hello world's  end!!\n\n  123456 x",
+        "I'M sure you'll parseJSONData, don'T.",
+        "  fn main() {\n    println!(\"hi\");\n}\n",
+        "naïve café 東京タワー 2024年 ok?!/\n/path",
+        "'sat HTTPServer x'Re\r\n\r\n  \tz",
+        "東D T東ǄTL4",
+        "日本語API ǅx Ⅻ3",
+    ];
+
+    #[test]
+    fn split_cl100k() {
+        let expected: [&[&str]; 7] = [
+            &["Hello", " world", "'s", " ", " end", "!!\n\n", " ", " ", "123", "456", " x"],
+            &["I", "'M", " sure", " you", "'ll", " parseJSONData", ",", " don", "'T", "."],
+            &[" ", " fn", " main", "()", " {\n", "   ", " println", "!(\"", "hi", "\");\n", "}\n"],
+            &["naïve", " café", " 東京タワー", " ", "202", "4", "年", " ok", "?!/\n", "/path"],
+            &["'s", "at", " HTTPServer", " x", "'Re", "\r\n\r\n", "  ", "\tz"],
+            &["東D", " T東ǄTL", "4"],
+            &["日本語API", " ǅx", " ", "Ⅻ3"],
+        ];
+        for (text, expected) in CASES.iter().zip(expected) {
+            assert_eq!(split(text, Pattern::Cl100k), expected);
+        }
+    }
+
+    #[test]
+    fn split_o200k() {
+        let expected: [&[&str]; 7] = [
+            &["Hello", " world's", " ", " end", "!!\n\n", " ", " ", "123", "456", " x"],
+            &["I'M", " sure", " you'll", " parse", "JSONData", ",", " don'T", "."],
+            &[" ", " fn", " main", "()", " {\n", "   ", " println", "!(\"", "hi", "\");\n", "}\n"],
+            &["naïve", " café", " 東京タワー", " ", "202", "4", "年", " ok", "?!/\n/", "path"],
+            &["'sat", " HTTPServer", " x'Re", "\r\n\r\n", "  ", "\tz"],
+            &["東", "D", " T東", "ǄTL", "4"],
+            &["日本語", "API", " ǅx", " ", "Ⅻ3"],
+        ];
+        for (text, expected) in CASES.iter().zip(expected) {
+            assert_eq!(split(text, Pattern::O200k), expected);
+        }
+    }
+}